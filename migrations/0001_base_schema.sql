@@ -0,0 +1,17 @@
+-- Base schema the api was originally deployed with. Everything is
+-- `IF NOT EXISTS` so this is a no-op against an existing database.
+CREATE TABLE IF NOT EXISTS users (
+    id UUID PRIMARY KEY,
+    username TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    saved_posts BIGINT[] NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS posts (
+    hash TEXT PRIMARY KEY,
+    post_id BIGINT NOT NULL,
+    title TEXT NOT NULL,
+    url TEXT NOT NULL,
+    author TEXT NOT NULL,
+    timestamp INTEGER NOT NULL
+);
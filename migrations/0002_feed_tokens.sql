@@ -0,0 +1,3 @@
+-- Secret token used to expose a user's saved posts as a feed.
+-- NULL means the user has no feed (or revoked it).
+ALTER TABLE users ADD COLUMN IF NOT EXISTS feed_token TEXT UNIQUE;
@@ -0,0 +1,4 @@
+-- Accounts can sign in with a username & password instead of a hash
+-- key, those accounts have no hash key.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash TEXT;
+ALTER TABLE users ALTER COLUMN hash DROP NOT NULL;
@@ -0,0 +1,3 @@
+-- Account keys are only stored as an argon2 hash, `hash` held the
+-- legacy keys from before (revoked by 0010).
+ALTER TABLE users ADD COLUMN IF NOT EXISTS key_hash TEXT;
@@ -0,0 +1,17 @@
+-- Usernames are unique, ignoring case. Anonymous accounts used to be able
+-- to share a username, so duplicates get the start of their id tacked on,
+-- password accounts (which sign in by username) keep theirs. Names are cut
+-- short first so they stay within the 32 characters allowed.
+UPDATE users SET username = left(username, 32 - 9) || '-' || left(replace(id::text, '-', ''), 8)
+WHERE id IN (
+    SELECT id FROM (
+        SELECT id, row_number() OVER (
+            PARTITION BY lower(username)
+            ORDER BY password_hash IS NULL, id
+        ) AS position
+        FROM users
+    ) AS ranked
+    WHERE position > 1
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_lower_key ON users (lower(username));
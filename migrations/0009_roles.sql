@@ -0,0 +1,6 @@
+-- Admins get the `/admin` routes, promote someone with
+-- `UPDATE users SET role = 'admin' WHERE username = '...'`.
+-- Disabled accounts can't sign in & their tokens stop working.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user'
+    CHECK (role IN ('user', 'admin'));
+ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled_at TIMESTAMPTZ;
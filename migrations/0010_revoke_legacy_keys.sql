@@ -0,0 +1,8 @@
+-- Legacy keys were derived from the user's id, which isn't secret, so they
+-- stop working along with any sessions signed in with them. Their owners get
+-- a new key from `/auth-actions/account/key` if they can sign in some other
+-- way, or from an admin (`/admin/users/{id}/key`) once they've shown the
+-- account is theirs.
+UPDATE refresh_tokens SET revoked_at = now()
+WHERE revoked_at IS NULL AND user_id IN (SELECT id FROM users WHERE hash IS NOT NULL);
+UPDATE users SET hash = NULL WHERE hash IS NOT NULL;
@@ -0,0 +1,137 @@
+use crate::error::EchoError;
+use actix_web::web;
+use anyhow::Context;
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long an access token is valid for, clients refresh it with their refresh token
+pub const ACCESS_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+/// How long a refresh token is valid for
+pub const REFRESH_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+
+/// Hashes a password (or account key) with argon2id into a PHC string for storage
+pub async fn hash_password(password: String) -> Result<String, EchoError> {
+    // Hashing is deliberately slow, so keep it off the async workers
+    let hashed = web::block(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .context("password hashing task failed")?
+    .context("failed to hash password")?;
+
+    Ok(hashed)
+}
+
+/// Checks a password against a PHC string from `hash_password`
+pub async fn verify_password(password: String, password_hash: String) -> Result<bool, EchoError> {
+    let verified = web::block(move || {
+        let parsed = PasswordHash::new(&password_hash)?;
+        Ok::<bool, argon2::password_hash::Error>(
+            Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        )
+    })
+    .await
+    .context("password verifying task failed")?
+    .context("stored password hash is malformed")?;
+
+    Ok(verified)
+}
+
+/// Hash of a random password that was thrown away, checked against when
+/// there's no account so an unknown username takes as long as a wrong password
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$pEzaIjlvpf70y764+on/cQ$5AO/If+kcc1xeCryeFhGDqhyMnxHoJFj2UAdqAD0XeA";
+
+/// Checks a password against the account's hash, or against `DUMMY_HASH`
+/// when there's no account, which never verifies
+pub async fn verify_account_password(password: String, password_hash: Option<String>) -> Result<bool, EchoError> {
+    let found = password_hash.is_some();
+    let verified = verify_password(password, password_hash.unwrap_or_else(|| DUMMY_HASH.to_string())).await?;
+    Ok(found && verified)
+}
+
+/// Generates a new account key, `<user id>.<256 random bits>` as hex.
+///
+/// The key is only ever shown to its owner once & is stored with
+/// `hash_password`, the user id lets sign in find the hash to check.
+pub fn generate_key(user_id: Uuid) -> String {
+    format!("{}.{}", user_id.simple(), random_hex())
+}
+
+/// The user a key from `generate_key` belongs to, `None` for legacy keys
+pub fn key_owner(key: &str) -> Option<Uuid> {
+    let (user_id, _) = key.split_once('.')?;
+    Uuid::parse_str(user_id).ok()
+}
+
+/// Generates a new refresh token, 256 random bits as hex
+pub fn generate_refresh_token() -> String {
+    random_hex()
+}
+
+/// Generates a new CSRF token for a session, 256 random bits as hex
+pub fn generate_csrf_token() -> String {
+    random_hex()
+}
+
+/// Hash of a refresh token for storage, they're random enough
+/// to not need a slow hash like keys & passwords
+pub fn hash_refresh_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// PKCE (RFC 7636) S256 code challenge for a code verifier
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// 256 bits from the OS RNG as hex
+pub fn random_hex() -> String {
+    hex(&random_bytes())
+}
+
+/// 256 bits from the OS RNG as unpadded base64url, how WebAuthn wants its challenges
+pub fn random_base64url() -> String {
+    URL_SAFE_NO_PAD.encode(random_bytes())
+}
+
+fn random_bytes() -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn missing_accounts_never_verify() {
+        let password_hash = hash_password(String::from("hunter22")).await.unwrap();
+
+        assert!(verify_account_password(String::from("hunter22"), Some(password_hash.clone())).await.unwrap());
+        assert!(!verify_account_password(String::from("hunter2"), Some(password_hash.clone())).await.unwrap());
+        assert!(!verify_account_password(String::from("hunter22"), None).await.unwrap());
+
+        // Costs as much to check as the hashes we store
+        let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+        let stored = PasswordHash::new(&password_hash).unwrap();
+        assert_eq!((dummy.algorithm, dummy.params), (stored.algorithm, stored.params));
+    }
+}
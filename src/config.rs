@@ -0,0 +1,617 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{fmt, path::PathBuf, str::FromStr, time::Duration};
+
+
+// Settings come from, each overriding the last: the defaults below, a TOML
+// file (`--config`, `ECHO_CONFIG`, or `echo.toml` when there is one), the
+// environment, then command line flags. Secrets can't be passed as flags,
+// where anyone listing the server's processes would see them.
+
+/// Config file read when none is given
+const DEFAULT_CONFIG_PATH: &str = "echo.toml";
+
+/// Everything the server is configured with
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub jwt: JwtConfig,
+    pub oidc: OidcConfig,
+    pub webauthn: WebauthnConfig,
+    pub rate_limits: RateLimitConfig,
+    /// Where the website is served from, without the trailing `/`
+    pub site_url: String,
+    /// Whether accounts can sign up without a password, getting a hash key instead
+    pub anonymous_sign_up: bool,
+    /// `env_logger` filter
+    pub log: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Largest request body accepted, in bytes
+    pub max_payload_size: usize,
+    /// Whether to take the client's ip from `X-Forwarded-For`, only
+    /// safe behind a proxy that sets it
+    pub trust_forwarded_for: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: Option<String>,
+    pub max_connections: u32,
+    /// Longest a connection is kept before it's replaced, in seconds
+    pub max_lifetime_secs: u64,
+}
+
+/// Keys tokens are signed & verified with
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// The only (HS256) key when there's no `keys`
+    pub secret: Option<String>,
+    pub keys: Vec<JwtKeyConfig>,
+    /// Key new tokens are signed with, the first of the `keys` by default
+    pub signing_kid: Option<String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct JwtKeyConfig {
+    pub kid: String,
+    /// HS256, RS256 or EdDSA
+    pub algorithm: String,
+    /// The secret for HS256, or the path to a PKCS#8 PEM private key for RS256 & EdDSA
+    pub key: String,
+}
+
+/// The OpenID Connect provider users can sign in with, turned off without an issuer
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    pub issuer: Option<String>,
+    pub client_id: Option<String>,
+    /// Only for confidential clients
+    pub client_secret: Option<String>,
+    /// Our `/user/oidc/callback`, under the site url by default
+    pub redirect_url: String,
+}
+
+/// Who we are to passkey authenticators
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebauthnConfig {
+    /// Domain credentials are scoped to, the site url's by default
+    pub rp_id: String,
+    /// Origin the website is served from, the site url by default
+    pub origin: String,
+}
+
+/// Requests a minute, & failed sign ins before an account's locked out
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub auth_ip_per_minute: u32,
+    pub auth_account_per_minute: u32,
+    pub auth_lockout_after: u32,
+    pub user_per_minute: u32,
+    /// The feed fans out to our sources
+    pub posts_per_minute: u32,
+    pub auth_actions_per_minute: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerConfig::default(),
+            database: DatabaseConfig::default(),
+            jwt: JwtConfig::default(),
+            oidc: OidcConfig::default(),
+            webauthn: WebauthnConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            site_url: String::from("https://echo.antoniohickey.com"),
+            anonymous_sign_up: true,
+            log: String::from("info"),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: String::from("0.0.0.0"),
+            port: 8081,
+            max_payload_size: 256 * 1024,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { url: None, max_connections: 5, max_lifetime_secs: 6 }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            auth_ip_per_minute: 10,
+            auth_account_per_minute: 5,
+            auth_lockout_after: 5,
+            user_per_minute: 60,
+            posts_per_minute: 60,
+            auth_actions_per_minute: 60,
+        }
+    }
+}
+
+/// A setting that can come from the environment, & from
+/// the command line too unless it's a secret
+struct Setting {
+    env: &'static str,
+    flag: Option<&'static str>,
+    value: &'static str,
+    help: &'static str,
+}
+
+const fn setting(env: &'static str, flag: &'static str, value: &'static str, help: &'static str) -> Setting {
+    Setting { env, flag: Some(flag), value, help }
+}
+
+const fn secret(env: &'static str, help: &'static str) -> Setting {
+    Setting { env, flag: None, value: "", help }
+}
+
+const SETTINGS: &[Setting] = &[
+    setting("HOST", "--host", "<HOST>", "Address to listen on [default: 0.0.0.0]"),
+    setting("PORT", "--port", "<PORT>", "Port to listen on [default: 8081]"),
+    setting("MAX_PAYLOAD_SIZE", "--max-payload-size", "<BYTES>", "Largest request body accepted [default: 262144]"),
+    setting("TRUST_FORWARDED_FOR", "--trust-forwarded-for", "<BOOL>", "Take client ips from X-Forwarded-For [default: false]"),
+    setting("DB_URL", "--db-url", "<URL>", "Postgres connection url"),
+    setting("DB_MAX_CONNECTIONS", "--db-max-connections", "<N>", "Database pool size [default: 5]"),
+    setting("DB_MAX_LIFETIME_SECS", "--db-max-lifetime-secs", "<SECS>", "Longest a database connection is kept [default: 6]"),
+    setting("SITE_URL", "--site-url", "<URL>", "Where the website is served from [default: https://echo.antoniohickey.com]"),
+    setting("ANONYMOUS_SIGN_UP", "--anonymous-sign-up", "<BOOL>", "Allow passwordless sign ups [default: true]"),
+    setting("RUST_LOG", "--log", "<FILTER>", "Log filter, like `info` or `echo_api=debug` [default: info]"),
+    secret("JWT_SECRET", "The only (HS256) token key when there's no JWT_KEYS"),
+    secret("JWT_KEYS", "Token keys, a comma separated list of `kid:ALG:key`"),
+    setting("JWT_SIGNING_KID", "--jwt-signing-kid", "<KID>", "Key new tokens are signed with [default: the first key]"),
+    setting("OIDC_ISSUER", "--oidc-issuer", "<URL>", "OpenID Connect provider to sign in with [default: none]"),
+    setting("OIDC_CLIENT_ID", "--oidc-client-id", "<ID>", "Our client id with the provider"),
+    secret("OIDC_CLIENT_SECRET", "Our client secret with the provider, for confidential clients"),
+    setting("OIDC_REDIRECT_URL", "--oidc-redirect-url", "<URL>", "Our callback [default: <site url>/user/oidc/callback]"),
+    setting("WEBAUTHN_RP_ID", "--webauthn-rp-id", "<DOMAIN>", "Domain passkeys are scoped to [default: the site url's]"),
+    setting("WEBAUTHN_ORIGIN", "--webauthn-origin", "<URL>", "Origin passkeys are used from [default: the site url]"),
+    setting("AUTH_IP_PER_MINUTE", "--auth-ip-per-minute", "<N>", "Sign ins a minute per ip [default: 10]"),
+    setting("AUTH_ACCOUNT_PER_MINUTE", "--auth-account-per-minute", "<N>", "Sign ins a minute per account [default: 5]"),
+    setting("AUTH_LOCKOUT_AFTER", "--auth-lockout-after", "<N>", "Failed sign ins before an account's locked out [default: 5]"),
+    setting("RATE_LIMIT_USER_PER_MINUTE", "--rate-limit-user-per-minute", "<N>", "/user requests a minute [default: 60]"),
+    setting("RATE_LIMIT_POSTS_PER_MINUTE", "--rate-limit-posts-per-minute", "<N>", "/posts requests a minute [default: 60]"),
+    setting("RATE_LIMIT_AUTH_ACTIONS_PER_MINUTE", "--rate-limit-auth-actions-per-minute", "<N>", "/auth-actions requests a minute [default: 60]"),
+];
+
+fn usage() -> String {
+    let mut usage = String::from("Usage: echo_api [OPTIONS]\n\nOptions:\n");
+    let option = |usage: &mut String, name: String, help: &str| usage.push_str(&format!("  {:<44} {}\n", name, help));
+    option(&mut usage, String::from("--config <PATH>"), "TOML config file [env: ECHO_CONFIG] [default: echo.toml]");
+    for setting in SETTINGS {
+        if let Some(flag) = setting.flag {
+            option(&mut usage, format!("{} {}", flag, setting.value), &format!("{} [env: {}]", setting.help, setting.env));
+        }
+    }
+    option(&mut usage, String::from("-h, --help"), "Print this help");
+
+    usage.push_str("\nSecrets, only from the environment or the config file:\n");
+    for setting in SETTINGS.iter().filter(|setting| setting.flag.is_none()) {
+        option(&mut usage, setting.env.to_string(), setting.help);
+    }
+    usage
+}
+
+impl Config {
+    /// Loads & validates the config, or `None` when only asked for `--help`
+    pub fn load() -> Result<Option<Self>> {
+        let args = match Args::parse(std::env::args().skip(1))? {
+            Some(args) => args,
+            None => {
+                print!("{}", usage());
+                return Ok(None);
+            }
+        };
+
+        let mut config = match args.config.clone().or_else(|| env_var("ECHO_CONFIG").map(PathBuf::from)) {
+            Some(path) => Config::from_file(&path)?,
+            None => match PathBuf::from(DEFAULT_CONFIG_PATH) {
+                path if path.exists() => Config::from_file(&path)?,
+                _ => Config::default(),
+            },
+        };
+        config.apply_env(env_var)?;
+        config.apply_args(args)?;
+        config.validate()?;
+
+        Ok(Some(config))
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read config file `{}`", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("invalid config file `{}`", path.display()))
+    }
+
+    /// Overrides settings with the env vars `env_var` finds
+    fn apply_env(&mut self, env_var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        for setting in SETTINGS {
+            if let Some(val) = env_var(setting.env) {
+                self.set(setting.env, setting.env, val)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: Args) -> Result<()> {
+        for (setting, val) in args.settings {
+            self.set(setting.env, setting.flag.unwrap_or(setting.env), val)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the setting `env` names, `name` is where the value came from
+    fn set(&mut self, env: &str, name: &str, val: String) -> Result<()> {
+        let limits = &mut self.rate_limits;
+        match env {
+            "HOST" => self.server.host = val,
+            "PORT" => self.server.port = parse_setting(name, &val)?,
+            "MAX_PAYLOAD_SIZE" => self.server.max_payload_size = parse_setting(name, &val)?,
+            "TRUST_FORWARDED_FOR" => self.server.trust_forwarded_for = parse_setting(name, &val)?,
+            "DB_URL" => self.database.url = Some(val),
+            "DB_MAX_CONNECTIONS" => self.database.max_connections = parse_setting(name, &val)?,
+            "DB_MAX_LIFETIME_SECS" => self.database.max_lifetime_secs = parse_setting(name, &val)?,
+            "SITE_URL" => self.site_url = val,
+            "ANONYMOUS_SIGN_UP" => self.anonymous_sign_up = parse_setting(name, &val)?,
+            "RUST_LOG" => self.log = val,
+            "JWT_SECRET" => self.jwt.secret = Some(val),
+            "JWT_KEYS" => self.jwt.keys = parse_jwt_keys(&val)?,
+            "JWT_SIGNING_KID" => self.jwt.signing_kid = Some(val),
+            "OIDC_ISSUER" => self.oidc.issuer = Some(val),
+            "OIDC_CLIENT_ID" => self.oidc.client_id = Some(val),
+            "OIDC_CLIENT_SECRET" => self.oidc.client_secret = Some(val),
+            "OIDC_REDIRECT_URL" => self.oidc.redirect_url = val,
+            "WEBAUTHN_RP_ID" => self.webauthn.rp_id = val,
+            "WEBAUTHN_ORIGIN" => self.webauthn.origin = val,
+            "AUTH_IP_PER_MINUTE" => limits.auth_ip_per_minute = parse_setting(name, &val)?,
+            "AUTH_ACCOUNT_PER_MINUTE" => limits.auth_account_per_minute = parse_setting(name, &val)?,
+            "AUTH_LOCKOUT_AFTER" => limits.auth_lockout_after = parse_setting(name, &val)?,
+            "RATE_LIMIT_USER_PER_MINUTE" => limits.user_per_minute = parse_setting(name, &val)?,
+            "RATE_LIMIT_POSTS_PER_MINUTE" => limits.posts_per_minute = parse_setting(name, &val)?,
+            "RATE_LIMIT_AUTH_ACTIONS_PER_MINUTE" => limits.auth_actions_per_minute = parse_setting(name, &val)?,
+            _ => unreachable!("every setting in SETTINGS is handled"),
+        }
+        Ok(())
+    }
+
+    /// Checks the settings make sense, so a bad config stops the server from
+    /// starting rather than failing on some later request. Settings left to
+    /// default to the site url's are filled in.
+    fn validate(&mut self) -> Result<()> {
+        if self.server.host.trim().is_empty() {
+            bail!("server host can't be empty");
+        }
+        if self.server.port == 0 {
+            bail!("server port must be between 1 & 65535");
+        }
+        if self.server.max_payload_size == 0 {
+            bail!("max payload size must be at least 1 byte");
+        }
+        match self.database.url.as_deref() {
+            Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {}
+            Some(_) => bail!("database url must be a `postgres://` url"),
+            None => bail!("a database url is required, set DB_URL, `database.url` or --db-url"),
+        }
+        if self.database.max_connections == 0 {
+            bail!("database max connections must be at least 1");
+        }
+        if self.database.max_lifetime_secs == 0 {
+            bail!("database max lifetime must be at least 1 second");
+        }
+        if self.log.trim().is_empty() {
+            bail!("log filter can't be empty");
+        }
+
+        self.site_url = self.site_url.trim_end_matches('/').to_string();
+        let site_host = match url_host(&self.site_url) {
+            Some(host) => host.to_string(),
+            None => bail!("site url must be an http(s) url, got `{}`", self.site_url),
+        };
+
+        if self.jwt.secret.as_deref().is_some_and(str::is_empty) {
+            bail!("token secret can't be empty");
+        }
+        if self.jwt.secret.is_none() && self.jwt.keys.is_empty() {
+            bail!("a token key is required, set JWT_KEYS or JWT_SECRET, or `jwt.keys` or `jwt.secret`");
+        }
+
+        if self.oidc.issuer.is_some() {
+            if self.oidc.client_id.is_none() {
+                bail!("an OpenID Connect client id is required with an issuer, set OIDC_CLIENT_ID or `oidc.client_id`");
+            }
+            if self.oidc.redirect_url.is_empty() {
+                self.oidc.redirect_url = format!("{}/user/oidc/callback", self.site_url);
+            }
+            if url_host(&self.oidc.redirect_url).is_none() {
+                bail!("OpenID Connect redirect url must be an http(s) url, got `{}`", self.oidc.redirect_url);
+            }
+        }
+
+        if self.webauthn.origin.is_empty() {
+            self.webauthn.origin = self.site_url.clone();
+        }
+        if self.webauthn.rp_id.is_empty() {
+            // The rp id is a bare domain, no port
+            self.webauthn.rp_id = site_host.split(':').next().unwrap_or_default().to_string();
+        }
+        if url_host(&self.webauthn.origin).is_none() {
+            bail!("passkey origin must be an http(s) url, got `{}`", self.webauthn.origin);
+        }
+
+        let limits = &self.rate_limits;
+        for (name, limit) in [
+            ("auth_ip_per_minute", limits.auth_ip_per_minute),
+            ("auth_account_per_minute", limits.auth_account_per_minute),
+            ("auth_lockout_after", limits.auth_lockout_after),
+            ("user_per_minute", limits.user_per_minute),
+            ("posts_per_minute", limits.posts_per_minute),
+            ("auth_actions_per_minute", limits.auth_actions_per_minute),
+        ] {
+            if limit == 0 {
+                bail!("rate limit {} must be at least 1", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Database url, always set once the config is validated
+    pub fn db_url(&self) -> &str {
+        self.database.url.as_deref().unwrap_or_default()
+    }
+
+    pub fn db_max_lifetime(&self) -> Duration {
+        Duration::from_secs(self.database.max_lifetime_secs)
+    }
+}
+
+// Secrets stay out of debug output
+impl fmt::Debug for JwtConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JwtConfig")
+            .field("kids", &self.keys.iter().map(|key| &key.kid).collect::<Vec<_>>())
+            .field("signing_kid", &self.signing_kid)
+            .finish()
+    }
+}
+impl fmt::Debug for JwtKeyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JwtKeyConfig").field("kid", &self.kid).field("algorithm", &self.algorithm).finish()
+    }
+}
+impl fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OidcConfig")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("redirect_url", &self.redirect_url)
+            .finish()
+    }
+}
+
+/// Parses `JWT_KEYS`, a comma separated list of `kid:ALG:key`
+fn parse_jwt_keys(spec: &str) -> Result<Vec<JwtKeyConfig>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(3, ':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(kid), Some(algorithm), Some(key)) if !kid.is_empty() && !key.is_empty() => Ok(JwtKeyConfig {
+                    kid: kid.to_string(),
+                    algorithm: algorithm.to_string(),
+                    key: key.to_string(),
+                }),
+                (kid, ..) => bail!("JWT_KEYS entry for kid `{}` must look like `kid:ALG:key`", kid.unwrap_or_default()),
+            }
+        })
+        .collect()
+}
+
+/// The host (& port) of an http(s) url
+fn url_host(url: &str) -> Option<&str> {
+    let rest = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"))?;
+    let host = rest.split('/').next().unwrap_or_default();
+    (!host.is_empty() && !rest.contains(char::is_whitespace)).then_some(host)
+}
+
+/// Command line flags, in the order they're given
+#[derive(Default)]
+struct Args {
+    config: Option<PathBuf>,
+    settings: Vec<(&'static Setting, String)>,
+}
+
+impl Args {
+    /// Parses `--flag value` & `--flag=value` flags, `None` for `--help`
+    fn parse(args: impl Iterator<Item = String>) -> Result<Option<Self>> {
+        let mut parsed = Args::default();
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            }
+            let (flag, inline_val) = match arg.split_once('=') {
+                Some((flag, val)) => (flag.to_string(), Some(val.to_string())),
+                None => (arg, None),
+            };
+            let setting = SETTINGS.iter().find(|setting| setting.flag == Some(flag.as_str()));
+            if flag != "--config" && setting.is_none() {
+                bail!("unknown argument `{}`, see --help", flag);
+            }
+            let val = match inline_val.or_else(|| args.next_if(|next| !next.starts_with("--"))) {
+                Some(val) => val,
+                None => bail!("{} needs a value, see --help", flag),
+            };
+
+            match setting {
+                Some(setting) => parsed.settings.push((setting, val)),
+                None => parsed.config = Some(PathBuf::from(val)),
+            }
+        }
+        Ok(Some(parsed))
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|val| !val.is_empty())
+}
+
+fn parse_setting<T: FromStr>(name: &str, val: &str) -> Result<T> {
+    match val.parse() {
+        Ok(val) => Ok(val),
+        Err(_) => bail!("invalid value `{}` for {}", val, name),
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// The defaults, plus the settings that have none
+    pub fn for_tests() -> Self {
+        let mut config = Config::default();
+        config.database.url = Some(String::from("postgres://echo@localhost/echo"));
+        config.jwt.secret = Some(String::from("secret"));
+        config.validate().unwrap();
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Result<Option<Args>> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// A config that validates, from the file, env & flags given
+    fn load(file: &str, env: &[(&str, &str)], flags: &[&str]) -> Result<Config> {
+        let env: HashMap<String, String> = env.iter().map(|(name, val)| (name.to_string(), val.to_string())).collect();
+        let mut config: Config = toml::from_str(file)?;
+        config.apply_env(|name| env.get(name).cloned())?;
+        config.apply_args(args(flags)?.unwrap())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    const MINIMAL: &str = "[database]\nurl = \"postgres://echo@localhost/echo\"\n[jwt]\nsecret = \"secret\"\n";
+
+    #[test]
+    fn defaults() {
+        let config = load(MINIMAL, &[], &[]).unwrap();
+
+        assert_eq!((config.server.host.as_str(), config.server.port), ("0.0.0.0", 8081));
+        assert_eq!(config.server.max_payload_size, 262_144);
+        assert_eq!(config.database.max_connections, 5);
+        assert_eq!(config.db_max_lifetime(), Duration::from_secs(6));
+        assert_eq!(config.log, "info");
+        assert!(config.anonymous_sign_up && !config.server.trust_forwarded_for);
+        assert_eq!(config.rate_limits.auth_ip_per_minute, 10);
+        assert!(config.oidc.issuer.is_none());
+        // Passkeys are for the site
+        assert_eq!(config.webauthn.rp_id, "echo.antoniohickey.com");
+        assert_eq!(config.webauthn.origin, "https://echo.antoniohickey.com");
+    }
+
+    #[test]
+    fn env_overrides_file_and_flags_override_env() {
+        let file = format!("{}[server]\nport = 9000\nhost = \"127.0.0.1\"\n[rate_limits]\nuser_per_minute = 7\n", MINIMAL);
+        let env = [("PORT", "9001"), ("RATE_LIMIT_USER_PER_MINUTE", "8"), ("SITE_URL", "http://localhost:9001/")];
+        let config = load(&file, &env, &["--port", "9002"]).unwrap();
+
+        assert_eq!(config.server.port, 9002);
+        assert_eq!(config.server.host, "127.0.0.1");
+        assert_eq!(config.rate_limits.user_per_minute, 8);
+        assert_eq!(config.site_url, "http://localhost:9001");
+        assert_eq!(config.webauthn.rp_id, "localhost");
+        assert_eq!(config.webauthn.origin, "http://localhost:9001");
+
+        let config = load(&file, &env, &["--rate-limit-user-per-minute=9", "--site-url", "https://echo.test"]).unwrap();
+        assert_eq!(config.server.port, 9001);
+        assert_eq!(config.rate_limits.user_per_minute, 9);
+        assert_eq!(config.site_url, "https://echo.test");
+    }
+
+    #[test]
+    fn secrets_come_from_env_or_file() {
+        let env = [("JWT_KEYS", "new:EdDSA:/keys/new.pem, old:HS256:shh"), ("OIDC_ISSUER", "https://id.test"), ("OIDC_CLIENT_ID", "echo")];
+        let config = load(MINIMAL, &env, &[]).unwrap();
+
+        let kids: Vec<&str> = config.jwt.keys.iter().map(|key| key.kid.as_str()).collect();
+        assert_eq!(kids, ["new", "old"]);
+        assert_eq!(config.jwt.keys[1].key, "shh");
+        assert_eq!(config.oidc.redirect_url, "https://echo.antoniohickey.com/user/oidc/callback");
+        assert!(!format!("{:?}", config).contains("shh"));
+
+        assert!(args(&["--jwt-secret", "shh"]).is_err());
+        assert!(args(&["--oidc-client-secret=shh"]).is_err());
+    }
+
+    #[test]
+    fn parses_flags() {
+        let parsed = args(&["--config", "echo.toml", "--port=80", "--host", "::", "--site-url=https://a.test/?b=c"]).unwrap().unwrap();
+
+        assert_eq!(parsed.config, Some(PathBuf::from("echo.toml")));
+        let settings: Vec<(&str, &str)> = parsed.settings.iter().map(|(setting, val)| (setting.env, val.as_str())).collect();
+        assert_eq!(settings, [("PORT", "80"), ("HOST", "::"), ("SITE_URL", "https://a.test/?b=c")]);
+        assert!(args(&["--port", "80", "--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_flags() {
+        for bad in [&["--bogus", "1"][..], &["port", "80"], &["--port"], &["--port", "--host", "::"], &["--config"]] {
+            assert!(args(bad).is_err(), "{:?}", bad);
+        }
+        assert!(load(MINIMAL, &[], &["--port", "eighty"]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        for env in [
+            ("PORT", "0"),
+            ("MAX_PAYLOAD_SIZE", "0"),
+            ("HOST", " "),
+            ("DB_URL", "mysql://echo@localhost/echo"),
+            ("DB_MAX_CONNECTIONS", "0"),
+            ("DB_MAX_LIFETIME_SECS", "0"),
+            ("RATE_LIMIT_POSTS_PER_MINUTE", "0"),
+            ("AUTH_LOCKOUT_AFTER", "-1"),
+            ("OIDC_ISSUER", "https://id.test"),
+            ("SITE_URL", "echo.test"),
+            ("WEBAUTHN_ORIGIN", "echo.test"),
+            ("ANONYMOUS_SIGN_UP", "nope"),
+            ("JWT_KEYS", "missing-key:HS256"),
+        ] {
+            assert!(load(MINIMAL, &[env], &[]).is_err(), "{:?}", env);
+        }
+        assert!(load(&format!("log = \" \"\n{}", MINIMAL), &[], &[]).is_err());
+        assert!(load("[jwt]\nsecret = \"secret\"\n", &[], &[]).is_err());
+        assert!(load("[database]\nurl = \"postgres://echo@localhost/echo\"\n[jwt]\nsecret = \"\"\n", &[], &[]).is_err());
+        assert!(load("[database]\nurl = \"postgres://echo@localhost/echo\"\n", &[], &[]).is_err());
+        // Typos in the file are caught rather than ignored
+        assert!(load(&format!("[sever]\nport = 80\n{}", MINIMAL), &[], &[]).is_err());
+    }
+}
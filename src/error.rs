@@ -1,23 +1,235 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
-use actix_web_httpauth::{extractors::AuthenticationError, headers::www_authenticate::bearer::Bearer};
+use actix_web::{
+    dev::ServiceResponse,
+    error::{JsonPayloadError, UrlencodedError},
+    http::{header, StatusCode},
+    middleware::ErrorHandlerResponse,
+    HttpRequest, HttpResponse, ResponseError,
+};
+use actix_web_httpauth::{
+    extractors::AuthenticationError,
+    headers::www_authenticate::{bearer::Bearer, WwwAuthenticate},
+};
+use askama::Template;
+use serde::Serialize;
+
+use crate::{routes::negotiate::wants_json, structs::{FieldError, ValidationErrors}, templates};
 
 /// Echo Error
 #[derive(Debug)]
 pub enum EchoError {
     AnyhowError(anyhow::Error),
     SqlError(sqlx::Error),
-    AuthError(AuthenticationError<Bearer>)
+    AuthError(AuthenticationError<Bearer>),
+    TemplateError(askama::Error),
+    IoError(std::io::Error),
+    /// The named thing doesn't exist
+    NotFound(&'static str),
+    /// The request had invalid fields
+    Validation(ValidationErrors),
+    /// A source we pull posts from failed us
+    Upstream(reqwest::Error),
+    /// The request needs a signed in user
+    Unauthorized,
+    /// The signed in user isn't allowed to do this
+    Forbidden(&'static str),
+    /// Too many requests, try again after the duration
+    RateLimited(Duration),
+    /// The request body is over the limit, in bytes
+    PayloadTooLarge(usize),
 }
 // Implement display trait for `EchoError`
 impl fmt::Display for EchoError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // TODO: Think of more meaningful display messages
-        write!(f, "error")
+        match self {
+            EchoError::AnyhowError(err) => write!(f, "{}", err),
+            EchoError::SqlError(err) => write!(f, "database error: {}", err),
+            EchoError::AuthError(err) => write!(f, "authentication error: {}", err),
+            EchoError::TemplateError(err) => write!(f, "template error: {}", err),
+            EchoError::IoError(err) => write!(f, "io error: {}", err),
+            EchoError::NotFound(what) => write!(f, "{} not found", what),
+            EchoError::Validation(invalid) => {
+                let fields: Vec<&str> = invalid.errors.iter().map(|error| error.field).collect();
+                write!(f, "invalid fields: {}", fields.join(", "))
+            }
+            EchoError::Upstream(err) => write!(f, "upstream source error: {}", err),
+            EchoError::Unauthorized => write!(f, "missing or invalid credentials"),
+            EchoError::Forbidden(reason) => write!(f, "forbidden: {}", reason),
+            EchoError::RateLimited(retry_after) => {
+                write!(f, "too many requests, try again in {} seconds", retry_after_secs(retry_after))
+            }
+            EchoError::PayloadTooLarge(limit) => write!(f, "request body is larger than {} bytes", limit),
+        }
     }
 }
+impl EchoError {
+    /// Message that's safe to show to the client, internal
+    /// failures get a generic message rather than their details
+    fn client_message(&self) -> String {
+        match self {
+            EchoError::Upstream(_) => String::from("Couldn't reach the source of our posts, try again later"),
+            EchoError::IoError(_) if !self.status_code().is_server_error() => String::from("Not found"),
+            _ if self.status_code().is_server_error() => String::from("Something went wrong on our end"),
+            _ => self.to_string(),
+        }
+    }
+
+    fn field_errors(&self) -> Option<&[FieldError]> {
+        match self {
+            EchoError::Validation(invalid) => Some(&invalid.errors),
+            _ => None,
+        }
+    }
+
+    /// Starts a response with the error's status & headers
+    fn response_builder(&self) -> actix_web::HttpResponseBuilder {
+        let mut response = HttpResponse::build(self.status_code());
+        response.insert_header((header::VARY, "Accept"));
+        match self {
+            EchoError::RateLimited(retry_after) => {
+                response.insert_header((header::RETRY_AFTER, retry_after_secs(retry_after)));
+            }
+            // RFC 6750, 401s say how to authenticate
+            EchoError::AuthError(err) => {
+                // Its own response has the challenge, with the configured realm & scope
+                if let Some(challenge) = err.error_response().headers().get(header::WWW_AUTHENTICATE) {
+                    response.insert_header((header::WWW_AUTHENTICATE, challenge.clone()));
+                }
+            }
+            EchoError::Unauthorized => {
+                response.insert_header(WwwAuthenticate(Bearer::default()));
+            }
+            _ => {}
+        }
+        response
+    }
+
+    /// The error as an RFC 7807 `application/problem+json` response
+    fn problem_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let problem = Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: self.client_message(),
+            errors: self.field_errors(),
+        };
+
+        self.response_builder()
+            .content_type("application/problem+json")
+            .body(serde_json::to_string(&problem).unwrap_or_default())
+    }
+}
+
+/// Whole seconds to wait, rounded up so clients don't retry too soon
+fn retry_after_secs(retry_after: &Duration) -> u64 {
+    (retry_after.as_secs_f64().ceil() as u64).max(1)
+}
+
+/// RFC 7807 problem details
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a [FieldError]>,
+}
+
 /// Implement `ResponseError` for actix-web Responder
-impl actix_web::error::ResponseError for EchoError {}
+impl ResponseError for EchoError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            EchoError::AnyhowError(_) | EchoError::SqlError(_) | EchoError::TemplateError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            EchoError::IoError(err) if err.kind() == std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            EchoError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            EchoError::AuthError(_) | EchoError::Unauthorized => StatusCode::UNAUTHORIZED,
+            EchoError::Forbidden(_) => StatusCode::FORBIDDEN,
+            EchoError::NotFound(_) => StatusCode::NOT_FOUND,
+            EchoError::Validation(_) => StatusCode::BAD_REQUEST,
+            EchoError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            EchoError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            EchoError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+
+    /// Renders the error as an htmx fragment, `negotiate_error_response`
+    /// swaps it for problem+json when the client asked for JSON.
+    fn error_response(&self) -> HttpResponse {
+        let html = match self.field_errors() {
+            Some(errors) => templates::FieldErrors { errors }.render(),
+            None => templates::Error { message: &self.client_message() }.render(),
+        };
+
+        match html {
+            Ok(html) => self.response_builder().content_type("text/html; charset=utf-8").body(html),
+            Err(_) => self.problem_response(),
+        }
+    }
+}
+
+/// `ErrorHandlers` hook logging every `EchoError` along with the request
+/// it failed, & responding with problem+json instead of an html fragment
+/// when the client wants JSON.
+pub fn negotiate_error_response<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let err = match res.response().error().and_then(|err| err.as_error::<EchoError>()) {
+        Some(err) => err,
+        None => return Ok(ErrorHandlerResponse::Response(res.map_into_left_body())),
+    };
+
+    let (method, path, status) = (res.request().method(), res.request().path(), err.status_code());
+    if status.is_server_error() {
+        log::error!("request failed: method={} path={} status={} error={:?}", method, path, status.as_u16(), err);
+    } else {
+        log::debug!("request rejected: method={} path={} status={} error={}", method, path, status.as_u16(), err);
+    }
+
+    if !wants_json(res.request()) {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+    let mut problem = err.problem_response();
+
+    // Headers middleware added on the way out (`RateLimit-*`, ..) carry over
+    let (req, res) = res.into_parts();
+    for (name, value) in res.headers() {
+        if *name != header::CONTENT_TYPE && *name != header::CONTENT_LENGTH && !problem.headers().contains_key(name) {
+            problem.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+    Ok(ErrorHandlerResponse::Response(ServiceResponse::new(req, problem).map_into_right_body()))
+}
+
+/// `FormConfig` error handler, so forms that don't parse get the same
+/// field errors (& problem+json) as forms with invalid fields
+pub fn form_error(err: UrlencodedError, _req: &HttpRequest) -> actix_web::Error {
+    let message = match &err {
+        UrlencodedError::ContentType => String::from("must be application/x-www-form-urlencoded"),
+        UrlencodedError::Overflow { limit, .. } => return EchoError::PayloadTooLarge(*limit).into(),
+        UrlencodedError::Parse(err) => err.to_string(),
+        _ => return err.into(),
+    };
+    EchoError::from(ValidationErrors { errors: vec![FieldError::new("body", message)] }).into()
+}
+
+/// `JsonConfig` error handler, so JSON bodies that don't parse get the
+/// same field errors (& 413s) as forms do
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let message = match &err {
+        JsonPayloadError::ContentType => String::from("must be application/json"),
+        JsonPayloadError::Overflow { limit } | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+            return EchoError::PayloadTooLarge(*limit).into()
+        }
+        JsonPayloadError::Deserialize(err) => err.to_string(),
+        _ => return err.into(),
+    };
+    EchoError::from(ValidationErrors { errors: vec![FieldError::new("body", message)] }).into()
+}
+
 /// Implement error conversion (`anyhow::Error` -> `EchoError`)
 impl From<anyhow::Error> for EchoError {
     fn from(err: anyhow::Error) -> EchoError {
@@ -36,3 +248,74 @@ impl From<AuthenticationError<Bearer>> for EchoError {
         EchoError::AuthError(err)
     }
 }
+/// Implement error conversion (`askama::Error` -> `EchoError`)
+impl From<askama::Error> for EchoError {
+    fn from(err: askama::Error) -> EchoError {
+        EchoError::TemplateError(err)
+    }
+}
+/// Implement error conversion (`std::io::Error` -> `EchoError`)
+impl From<std::io::Error> for EchoError {
+    fn from(err: std::io::Error) -> EchoError {
+        EchoError::IoError(err)
+    }
+}
+/// Implement error conversion (`reqwest::Error` -> `EchoError`)
+impl From<reqwest::Error> for EchoError {
+    fn from(err: reqwest::Error) -> EchoError {
+        EchoError::Upstream(err)
+    }
+}
+/// Implement error conversion (`ValidationErrors` -> `EchoError`)
+impl From<ValidationErrors> for EchoError {
+    fn from(invalid: ValidationErrors) -> EchoError {
+        EchoError::Validation(invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web_httpauth::extractors::bearer;
+
+    #[test]
+    fn unauthorized_responses_have_a_bearer_challenge() {
+        let invalid_token = EchoError::from(AuthenticationError::from(bearer::Config::default().scope("")));
+
+        for err in [EchoError::Unauthorized, invalid_token] {
+            for res in [err.error_response(), err.problem_response()] {
+                assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+                let challenge = res.headers().get(header::WWW_AUTHENTICATE).unwrap().to_str().unwrap();
+                assert!(challenge.starts_with("Bearer"), "{}", challenge);
+            }
+        }
+        let res = EchoError::Forbidden("admins only").error_response();
+        assert!(!res.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+
+    #[test]
+    fn unparsable_json_is_a_field_error() {
+        #[derive(serde::Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Body {
+            username: String,
+        }
+        let req = TestRequest::default().to_http_request();
+
+        let errors = [
+            JsonPayloadError::ContentType,
+            JsonPayloadError::Deserialize(serde_json::from_str::<Body>("{}").unwrap_err()),
+            JsonPayloadError::Deserialize(serde_json::from_str::<Body>("{\"username\": ").unwrap_err()),
+        ];
+        for err in errors {
+            let err = json_error(err, &req);
+            let echo_err = err.as_error::<EchoError>().unwrap();
+            assert_eq!(echo_err.status_code(), StatusCode::BAD_REQUEST);
+            assert_eq!(echo_err.field_errors().unwrap()[0].field, "body");
+        }
+
+        let err = json_error(JsonPayloadError::Overflow { limit: 16 }, &req);
+        assert_eq!(err.as_response_error().status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::routes::posts::Post;
+use atom_syndication::{Entry, Feed, FixedDateTime, Link, Person, Text};
+use chrono::{DateTime, Utc};
+use rss::{extension::dublincore::DublinCoreExtension, Channel, Guid, Item};
+use serde::Serialize;
+
+
+/// Builds an Atom document out of a list of posts
+pub fn atom_feed(title: &str, feed_url: &str, posts: &[Post]) -> String {
+    let entries: Vec<Entry> = posts.iter().map(atom_entry).collect();
+
+    // The feed was last updated whenever its newest entry was
+    let updated = entries
+        .iter()
+        .map(|entry| entry.updated)
+        .max()
+        .unwrap_or_else(|| Utc::now().fixed_offset());
+
+    let feed = Feed {
+        title: Text::plain(title),
+        id: feed_url.to_string(),
+        updated,
+        links: vec![Link {
+            href: feed_url.to_string(),
+            rel: "self".to_string(),
+            mime_type: Some("application/atom+xml".to_string()),
+            ..Default::default()
+        }],
+        entries,
+        ..Default::default()
+    };
+
+    feed.to_string()
+}
+
+fn atom_entry(post: &Post) -> Entry {
+    Entry {
+        id: post.permalink(),
+        title: Text::plain(post.title.as_str()),
+        updated: post_datetime(post),
+        authors: vec![Person {
+            name: post.author.clone(),
+            ..Default::default()
+        }],
+        links: vec![Link {
+            href: post.link(),
+            rel: "alternate".to_string(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+/// Builds an RSS 2.0 document out of a list of posts
+pub fn rss_feed(title: &str, home_url: &str, posts: &[Post]) -> String {
+    let channel = Channel {
+        title: title.to_string(),
+        link: home_url.to_string(),
+        description: "Curated Content Feed".to_string(),
+        items: posts.iter().map(rss_item).collect(),
+        ..Default::default()
+    };
+
+    channel.to_string()
+}
+
+fn rss_item(post: &Post) -> Item {
+    Item {
+        title: Some(post.title.clone()),
+        link: Some(post.link()),
+        comments: Some(post.permalink()),
+        guid: Some(Guid {
+            value: post.permalink(),
+            permalink: true,
+        }),
+        pub_date: Some(post_datetime(post).to_rfc2822()),
+        // RSS wants an email for `author`, so name the author with dublin core instead
+        dublin_core_ext: Some(DublinCoreExtension {
+            creators: vec![post.author.clone()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// JSON Feed (https://www.jsonfeed.org/version/1.1/)
+#[derive(Serialize, Debug)]
+pub struct JsonFeed {
+    version: &'static str,
+    title: String,
+    home_page_url: String,
+    feed_url: String,
+    items: Vec<JsonFeedItem>,
+}
+
+#[derive(Serialize, Debug)]
+struct JsonFeedItem {
+    id: String,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    external_url: Option<String>,
+    title: String,
+    date_published: String,
+    authors: Vec<JsonFeedAuthor>,
+}
+
+#[derive(Serialize, Debug)]
+struct JsonFeedAuthor {
+    name: String,
+}
+
+/// Builds a JSON Feed 1.1 document out of a list of posts
+pub fn json_feed(title: &str, home_url: &str, feed_url: &str, posts: &[Post]) -> JsonFeed {
+    JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: title.to_string(),
+        home_page_url: home_url.to_string(),
+        feed_url: feed_url.to_string(),
+        items: posts.iter().map(json_feed_item).collect(),
+    }
+}
+
+fn json_feed_item(post: &Post) -> JsonFeedItem {
+    JsonFeedItem {
+        id: post.id.clone(),
+        url: post.permalink(),
+        external_url: post.external_url(),
+        title: post.title.clone(),
+        date_published: post_datetime(post).to_rfc3339(),
+        authors: vec![JsonFeedAuthor {
+            name: post.author.clone(),
+        }],
+    }
+}
+
+/// Post timestamps are unix seconds stored as a string
+fn post_datetime(post: &Post) -> FixedDateTime {
+    post.timestamp
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .unwrap_or_default()
+        .fixed_offset()
+}
@@ -1,47 +1,86 @@
+mod auth;
+mod config;
 mod error;
+mod feed;
+mod keys;
+mod middleware;
+mod oidc;
+mod rate_limit;
 mod routes;
 mod structs;
+mod templates;
+mod webauthn;
 
 use actix_web::{web::Data, App, HttpServer};
-use actix_web::middleware::Logger;
+use actix_web::middleware::{from_fn, ErrorHandlers, Logger};
 use anyhow::Result;
 use dotenv::dotenv;
 use sqlx::postgres::PgPoolOptions;
-use std::{sync::Mutex, time::Duration};
+use std::sync::atomic::AtomicU32;
 use structs::AppState;
 
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    // Leave debug stuff for now
-    std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
     dotenv().ok();
 
+    // Settings from the config file, environment & command line
+    let config = match config::Config::load()? {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    env_logger::Builder::new().parse_filters(&config.log).init();
+
     // Create a connection pool to our database
-    let db_url = std::env::var("DB_URL")?;
     let db_pool = PgPoolOptions::new()
-        .max_connections(5)
-        .max_lifetime(Duration::new(6, 0)) // max db connection lifetime of 6 seconds for now
-        .connect(&db_url)
+        .max_connections(config.database.max_connections)
+        .max_lifetime(config.db_max_lifetime())
+        .connect(config.db_url())
         .await?;
 
-    // A shared app state among requests for tracking active connections,
-    // database connection pool, and the max payload size.
+    // Bring the database schema up to date
+    sqlx::migrate!().run(&db_pool).await?;
+
+    // Keys for signing & verifying tokens
+    let jwt_keys = keys::JwtKeys::from_config(&config.jwt)?;
+
+    // The OpenID Connect provider users can sign in with, if any
+    let oidc = oidc::OidcProvider::discover(&config.oidc).await?;
+
+    // Limits on sign in attempts, & request quotas for the rest of the api
+    let auth_limits = rate_limit::AuthLimits::new(&config.rate_limits, config.server.trust_forwarded_for);
+    let api_limits = rate_limit::ApiLimits::new(&config.rate_limits, config.server.trust_forwarded_for);
+
+    // A shared app state among requests for tracking requests in flight,
+    // database connection pool, the max payload size, whether hash key
+    // (passwordless) sign ups are allowed, where the website is, the
+    // token keys, the OpenID Connect provider, who we are to passkey
+    // authenticators, and the rate limits.
     let app_state = Data::new(AppState {
-        active_cnx: Mutex::new(0),
-        max_payload_size: 262_144,
+        in_flight_requests: AtomicU32::new(0),
+        max_payload_size: config.server.max_payload_size,
         db_pool,
+        anonymous_sign_up: config.anonymous_sign_up,
+        site_url: config.site_url.clone(),
+        jwt_keys,
+        oidc,
+        relying_party: webauthn::RelyingParty::new(&config.webauthn),
+        auth_limits,
+        api_limits,
     });
 
     // Build, Setup, & Start The Api (HTTP SERVER)
+    log::info!("listening: host={} port={}", config.server.host, config.server.port);
+    let max_payload_size = app_state.max_payload_size;
     Ok(HttpServer::new(move || {
         App::new()
+            .wrap(ErrorHandlers::new().default_handler(error::negotiate_error_response))
             .wrap(Logger::default())
+            .wrap(from_fn(middleware::track_in_flight))
             .app_data(app_state.clone())
-            .configure(routes::config::configure_routes)
+            .configure(|cfg| routes::config::configure_routes(cfg, max_payload_size))
     })
-    .bind(("0.0.0.0", 8081))?
+    .bind((config.server.host.as_str(), config.server.port))?
     .run()
     .await?)
 }
@@ -0,0 +1,33 @@
+use crate::structs::AppState;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web::Data,
+    Error,
+};
+use std::sync::atomic::Ordering;
+
+
+/// Counts the requests in flight for the stats, wraps the whole app
+pub async fn track_in_flight<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let _in_flight = req.app_data::<Data<AppState>>().cloned().map(InFlight::start);
+    next.call(req).await
+}
+
+/// A request in flight, until it's dropped (even when the request fails or is cancelled)
+struct InFlight(Data<AppState>);
+impl InFlight {
+    fn start(state: Data<AppState>) -> Self {
+        state.in_flight_requests.fetch_add(1, Ordering::Relaxed);
+        InFlight(state)
+    }
+}
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,402 @@
+use crate::{config::OidcConfig, error::EchoError};
+use anyhow::{bail, Context, Result};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::fmt;
+
+
+/// An OpenID Connect provider users can sign in with
+pub struct OidcProvider {
+    pub issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    http: reqwest::Client,
+}
+
+/// The parts of the provider's discovery document we use
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenEndpointResponse {
+    id_token: String,
+}
+
+/// Claims of the provider's ID token
+#[derive(Deserialize, Debug)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub preferred_username: Option<String>,
+}
+
+impl OidcProvider {
+    /// Discovers the provider at the issuer, signing in with it is turned
+    /// off when there isn't one. The client is registered with the provider
+    /// as the client id (& secret for confidential clients), redirecting
+    /// back to our `/user/oidc/callback`.
+    pub async fn discover(config: &OidcConfig) -> Result<Option<Self>> {
+        let issuer = match &config.issuer {
+            Some(issuer) => issuer.clone(),
+            None => return Ok(None),
+        };
+        let client_id = config.client_id.clone().context("an OpenID Connect client id is required with an issuer")?;
+
+        let http = reqwest::Client::new();
+        let discovery_url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+        let discovery: Discovery = http
+            .get(&discovery_url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .with_context(|| format!("failed to fetch {}", discovery_url))?
+            .json()
+            .await
+            .context("invalid OpenID Connect discovery document")?;
+
+        // The spec has the issuer match exactly, trailing slash & all
+        if discovery.issuer != issuer {
+            bail!("OpenID Connect issuer `{}` doesn't match the provider's issuer `{}`", issuer, discovery.issuer);
+        }
+
+        Ok(Some(OidcProvider {
+            issuer,
+            client_id,
+            client_secret: config.client_secret.clone(),
+            redirect_url: config.redirect_url.clone(),
+            authorization_endpoint: discovery.authorization_endpoint,
+            token_endpoint: discovery.token_endpoint,
+            jwks_uri: discovery.jwks_uri,
+            http,
+        }))
+    }
+
+    /// Where to send the user to sign in with the provider
+    pub fn authorization_url(&self, state: &str, nonce: &str, code_challenge: &str) -> Result<String, EchoError> {
+        let url = Url::parse_with_params(
+            &self.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_url),
+                ("scope", "openid profile"),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .context("invalid authorization endpoint")?;
+
+        Ok(url.into())
+    }
+
+    /// Trades the code the provider redirected back with for the user's
+    /// verified ID token claims, the token has to carry the sign in's `nonce`
+    pub async fn exchange_code(&self, code: &str, code_verifier: &str, nonce: &str) -> Result<IdTokenClaims, EchoError> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("client_id", &self.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &self.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let res = self.http.post(&self.token_endpoint).form(&form).send().await?;
+        // The provider rejecting the code is on the user, anything else is on the provider
+        if res.status() == StatusCode::BAD_REQUEST || res.status() == StatusCode::UNAUTHORIZED {
+            log::debug!("oidc code rejected: status={}", res.status().as_u16());
+            return Err(EchoError::Unauthorized);
+        }
+        let tokens: TokenEndpointResponse = res.error_for_status()?.json().await?;
+
+        self.verify_id_token(&tokens.id_token, nonce).await
+    }
+
+    async fn verify_id_token(&self, id_token: &str, nonce: &str) -> Result<IdTokenClaims, EchoError> {
+        let header = jsonwebtoken::decode_header(id_token).map_err(|_| EchoError::Unauthorized)?;
+        // Only the provider's published keys, no shared secrets
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(EchoError::Unauthorized);
+        }
+
+        let jwks: JwkSet = self.http.get(&self.jwks_uri).send().await?.error_for_status()?.json().await?;
+        let jwk = match header.kid.as_deref() {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }
+        .ok_or(EchoError::Unauthorized)?;
+        let key = DecodingKey::from_jwk(jwk).context("provider published an unusable key")?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_issuer(&[&self.issuer]);
+        let claims = jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|e| {
+                log::debug!("oidc id token rejected: error={}", e);
+                EchoError::Unauthorized
+            })?
+            .claims;
+        // Ties the token to this sign in, so it can't be replayed into another
+        if claims.nonce.as_deref() != Some(nonce) {
+            log::debug!("oidc id token rejected: error=nonce mismatch");
+            return Err(EchoError::Unauthorized);
+        }
+
+        Ok(claims)
+    }
+}
+// The client secret stays out of debug output
+impl fmt::Debug for OidcProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OidcProvider")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::{rand::SystemRandom, signature::{Ed25519KeyPair, KeyPair}};
+    use serde_json::{json, Value};
+    use std::{collections::HashMap, sync::Mutex};
+
+    const CLIENT_ID: &str = "echo";
+
+    /// A provider on localhost that hands out codes for whatever ID token
+    /// the test gives it, checking PKCE like a real one would
+    struct MockIdp {
+        issuer: String,
+        state: web::Data<Mutex<MockState>>,
+        encoding: EncodingKey,
+    }
+
+    struct MockState {
+        issuer: String,
+        jwk: Value,
+        /// Codes handed out, with the PKCE challenge & ID token they're for
+        codes: HashMap<String, (String, String)>,
+    }
+
+    async fn discovery(state: web::Data<Mutex<MockState>>) -> HttpResponse {
+        let issuer = state.lock().unwrap().issuer.clone();
+        HttpResponse::Ok().json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        }))
+    }
+
+    async fn token(state: web::Data<Mutex<MockState>>, form: web::Form<HashMap<String, String>>) -> HttpResponse {
+        let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+        let mut state = state.lock().unwrap();
+        match state.codes.remove(field("code")) {
+            Some((challenge, id_token)) if challenge == auth::pkce_challenge(field("code_verifier")) => {
+                HttpResponse::Ok().json(json!({ "id_token": id_token, "access_token": "at", "token_type": "Bearer" }))
+            }
+            _ => HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" })),
+        }
+    }
+
+    async fn jwks(state: web::Data<Mutex<MockState>>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({ "keys": [state.lock().unwrap().jwk.clone()] }))
+    }
+
+    impl MockIdp {
+        async fn start() -> Self {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            let jwk = json!({
+                "kty": "OKP", "crv": "Ed25519", "alg": "EdDSA", "use": "sig", "kid": "idp",
+                "x": URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+            });
+            let state = web::Data::new(Mutex::new(MockState { issuer: String::new(), jwk, codes: HashMap::new() }));
+
+            let app_state = state.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(app_state.clone())
+                    .route("/.well-known/openid-configuration", web::get().to(discovery))
+                    .route("/token", web::post().to(token))
+                    .route("/jwks", web::get().to(jwks))
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+            let issuer = format!("http://{}", server.addrs()[0]);
+            actix_web::rt::spawn(server.run());
+            state.lock().unwrap().issuer = issuer.clone();
+
+            MockIdp { issuer, state, encoding: EncodingKey::from_ed_der(pkcs8.as_ref()) }
+        }
+
+        async fn provider(&self) -> OidcProvider {
+            let config = OidcConfig {
+                issuer: Some(self.issuer.clone()),
+                client_id: Some(String::from(CLIENT_ID)),
+                client_secret: None,
+                redirect_url: String::from("https://echo.test/user/oidc/callback"),
+            };
+            OidcProvider::discover(&config).await.unwrap().unwrap()
+        }
+
+        /// What a provider signs, for the sign in's `nonce`
+        fn claims(&self, nonce: &str) -> Value {
+            let now = chrono::Utc::now().timestamp();
+            json!({
+                "iss": self.issuer, "aud": CLIENT_ID, "sub": "subject-1", "nonce": nonce,
+                "preferred_username": "someone", "iat": now, "exp": now + 300,
+            })
+        }
+
+        fn sign(&self, claims: &Value) -> String {
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(String::from("idp"));
+            jsonwebtoken::encode(&header, claims, &self.encoding).unwrap()
+        }
+
+        /// The user signing in, the provider redirects back with the code
+        fn authorize(&self, authorization_url: &str, id_token: String) -> String {
+            let url = Url::parse(authorization_url).unwrap();
+            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+            assert_eq!(params["code_challenge_method"], "S256");
+            assert_eq!(params["client_id"], CLIENT_ID);
+
+            let code = auth::random_hex();
+            self.state.lock().unwrap().codes.insert(code.clone(), (params["code_challenge"].clone(), id_token));
+            code
+        }
+    }
+
+    /// A sign in started by us, like `oidc_login` would
+    struct SignIn {
+        nonce: String,
+        code_verifier: String,
+        url: String,
+    }
+
+    fn start_sign_in(provider: &OidcProvider) -> SignIn {
+        let (state, nonce, code_verifier) = (auth::random_hex(), auth::random_hex(), auth::random_hex());
+        let url = provider.authorization_url(&state, &nonce, &auth::pkce_challenge(&code_verifier)).unwrap();
+        SignIn { nonce, code_verifier, url }
+    }
+
+    #[actix_web::test]
+    async fn signs_in() {
+        let idp = MockIdp::start().await;
+        let provider = idp.provider().await;
+        let sign_in = start_sign_in(&provider);
+
+        let code = idp.authorize(&sign_in.url, idp.sign(&idp.claims(&sign_in.nonce)));
+        let claims = provider.exchange_code(&code, &sign_in.code_verifier, &sign_in.nonce).await.unwrap();
+
+        assert_eq!(claims.sub, "subject-1");
+        assert_eq!(claims.preferred_username.as_deref(), Some("someone"));
+        // Codes are single use
+        let replayed = provider.exchange_code(&code, &sign_in.code_verifier, &sign_in.nonce).await;
+        assert!(matches!(replayed, Err(EchoError::Unauthorized)));
+    }
+
+    #[actix_web::test]
+    async fn rejects_pkce_verifier_mismatch() {
+        let idp = MockIdp::start().await;
+        let provider = idp.provider().await;
+        let sign_in = start_sign_in(&provider);
+
+        let code = idp.authorize(&sign_in.url, idp.sign(&idp.claims(&sign_in.nonce)));
+        let result = provider.exchange_code(&code, &auth::random_hex(), &sign_in.nonce).await;
+
+        assert!(matches!(result, Err(EchoError::Unauthorized)));
+    }
+
+    #[actix_web::test]
+    async fn rejects_nonce_mismatch() {
+        let idp = MockIdp::start().await;
+        let provider = idp.provider().await;
+        let sign_in = start_sign_in(&provider);
+
+        // A token from another sign in
+        let code = idp.authorize(&sign_in.url, idp.sign(&idp.claims(&auth::random_hex())));
+        let result = provider.exchange_code(&code, &sign_in.code_verifier, &sign_in.nonce).await;
+        assert!(matches!(result, Err(EchoError::Unauthorized)));
+
+        let mut claims = idp.claims(&sign_in.nonce);
+        claims.as_object_mut().unwrap().remove("nonce");
+        let code = idp.authorize(&sign_in.url, idp.sign(&claims));
+        let result = provider.exchange_code(&code, &sign_in.code_verifier, &sign_in.nonce).await;
+        assert!(matches!(result, Err(EchoError::Unauthorized)));
+    }
+
+    #[actix_web::test]
+    async fn rejects_hs256_id_tokens() {
+        let idp = MockIdp::start().await;
+        let provider = idp.provider().await;
+        let sign_in = start_sign_in(&provider);
+
+        // Signed with something the client knows, rather than the provider's key
+        for secret in [CLIENT_ID, ""] {
+            let id_token = jsonwebtoken::encode(
+                &Header::new(Algorithm::HS256),
+                &idp.claims(&sign_in.nonce),
+                &EncodingKey::from_secret(secret.as_bytes()),
+            )
+            .unwrap();
+            let code = idp.authorize(&sign_in.url, id_token);
+            let result = provider.exchange_code(&code, &sign_in.code_verifier, &sign_in.nonce).await;
+
+            assert!(matches!(result, Err(EchoError::Unauthorized)));
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_tokens_for_someone_else() {
+        let idp = MockIdp::start().await;
+        let provider = idp.provider().await;
+        let sign_in = start_sign_in(&provider);
+
+        let expired = chrono::Utc::now().timestamp() - 600;
+        for (claim, val) in [("aud", json!("another-client")), ("iss", json!("https://evil.test")), ("exp", json!(expired))] {
+            let mut claims = idp.claims(&sign_in.nonce);
+            claims[claim] = val;
+            let code = idp.authorize(&sign_in.url, idp.sign(&claims));
+            let result = provider.exchange_code(&code, &sign_in.code_verifier, &sign_in.nonce).await;
+
+            assert!(matches!(result, Err(EchoError::Unauthorized)), "{}", claim);
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_issuer_mismatch() {
+        let idp = MockIdp::start().await;
+        idp.state.lock().unwrap().issuer = String::from("https://someone-else.test");
+        let config = OidcConfig {
+            issuer: Some(idp.issuer.clone()),
+            client_id: Some(String::from(CLIENT_ID)),
+            client_secret: None,
+            redirect_url: String::from("https://echo.test/user/oidc/callback"),
+        };
+
+        assert!(OidcProvider::discover(&config).await.is_err());
+        assert!(OidcProvider::discover(&OidcConfig::default()).await.unwrap().is_none());
+    }
+}
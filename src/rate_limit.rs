@@ -0,0 +1,650 @@
+use crate::{
+    config::RateLimitConfig,
+    error::EchoError,
+    structs::{AppState, TokenClaims},
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web::Data,
+    Error, HttpMessage, HttpRequest, HttpResponse,
+};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+
+/// Keys tracked before stale ones, then the least recently seen, are cleared out
+const MAX_TRACKED: usize = 10_000;
+
+/// Token buckets by key (an ip, an account, ..), each holding up to
+/// `capacity` requests & refilling at `capacity` a minute
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+/// What's left of a key's quota after a request
+#[derive(Debug)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the quota's back to full
+    pub reset: Duration,
+    /// Set when the request went over the quota
+    pub retry_after: Option<Duration>,
+}
+
+impl Quota {
+    /// The quota with less left, going over if either went over
+    fn tighter(self, other: Quota) -> Quota {
+        let retry_after = self.retry_after.max(other.retry_after);
+        let mut tighter = match (other.remaining, other.reset) > (self.remaining, self.reset) {
+            true => self,
+            false => other,
+        };
+        tighter.retry_after = retry_after;
+        tighter
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn per_minute(capacity: u32) -> Self {
+        RateLimiter {
+            capacity: capacity as f64,
+            refill_per_sec: capacity as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a request out of the key's bucket, or how long until there's one to take
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        match self.take(key).retry_after {
+            Some(retry_after) => Err(retry_after),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes a request out of the key's bucket if there's one to take,
+    /// along with what's left of the key's quota
+    pub fn take(&self, key: &str) -> Quota {
+        self.take_all(&[key])
+    }
+
+    /// Takes a request out of every key's bucket if they all have one to
+    /// take, along with what's left of the quota with the least left
+    pub fn take_all(&self, keys: &[&str]) -> Quota {
+        self.take_all_at(keys, Instant::now())
+    }
+
+    fn take_all_at(&self, keys: &[&str], now: Instant) -> Quota {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if keys.iter().any(|key| !buckets.contains_key(*key)) {
+            // Full buckets are for clients that went quiet
+            make_room(
+                &mut buckets,
+                |bucket| self.refilled(bucket, now) >= self.capacity,
+                |bucket| bucket.updated_at,
+            );
+        }
+        for key in keys {
+            let bucket = buckets
+                .entry(key.to_string())
+                .or_insert(Bucket { tokens: self.capacity, updated_at: now });
+            bucket.tokens = self.refilled(bucket, now);
+            bucket.updated_at = now;
+        }
+
+        // Requests over any of the quotas don't count against the others
+        let over = keys.iter().any(|key| buckets[*key].tokens < 1.0);
+        keys.iter()
+            .map(|key| {
+                let bucket = buckets.get_mut(*key).expect("bucket was just added");
+                let retry_after = match (over, bucket.tokens >= 1.0) {
+                    (false, _) => {
+                        bucket.tokens -= 1.0;
+                        None
+                    }
+                    (true, true) => None,
+                    (true, false) => Some(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec)),
+                };
+                Quota {
+                    limit: self.capacity as u32,
+                    remaining: bucket.tokens.floor() as u32,
+                    reset: Duration::from_secs_f64((self.capacity - bucket.tokens) / self.refill_per_sec),
+                    retry_after,
+                }
+            })
+            .reduce(Quota::tighter)
+            .expect("keys to take from")
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity)
+    }
+}
+
+/// Accounts locked out after too many failed sign ins in a row, for
+/// twice as long with each failure past `after`
+#[derive(Debug)]
+pub struct Lockouts {
+    after: u32,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+    last_failed_at: Instant,
+}
+
+const LOCKOUT_BASE: Duration = Duration::from_secs(30);
+const LOCKOUT_MAX: Duration = Duration::from_secs(15 * 60);
+
+impl Lockouts {
+    pub fn new(after: u32) -> Self {
+        Lockouts { after, failures: Mutex::new(HashMap::new()) }
+    }
+
+    /// How long the account is still locked out for, if it is
+    pub fn check(&self, account: &str) -> Result<(), Duration> {
+        self.check_at(account, Instant::now())
+    }
+
+    fn check_at(&self, account: &str, now: Instant) -> Result<(), Duration> {
+        let failures = self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match failures.get(account).and_then(|failures| failures.locked_until) {
+            Some(locked_until) if locked_until > now => Err(locked_until - now),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&self, account: &str) {
+        self.record_failure_at(account, Instant::now())
+    }
+
+    fn record_failure_at(&self, account: &str, now: Instant) {
+        let mut failures = self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if !failures.contains_key(account) {
+            // Accounts that aren't locked out go first, then the lockouts closest to ending
+            make_room(
+                &mut failures,
+                |failures| failures.locked_until.is_none_or(|until| until <= now),
+                |failures| (failures.locked_until, failures.last_failed_at),
+            );
+        }
+        let entry = failures
+            .entry(account.to_string())
+            .or_insert(Failures { count: 0, locked_until: None, last_failed_at: now });
+        entry.count += 1;
+        entry.last_failed_at = now;
+
+        if entry.count >= self.after {
+            let doublings = (entry.count - self.after).min(16);
+            let lockout = LOCKOUT_BASE.saturating_mul(1 << doublings).min(LOCKOUT_MAX);
+            entry.locked_until = Some(now + lockout);
+            log::warn!("locked out account: account={} failures={} secs={}", account, entry.count, lockout.as_secs());
+        }
+    }
+
+    pub fn record_success(&self, account: &str) {
+        let mut failures = self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        failures.remove(account);
+    }
+}
+
+/// Makes room for a new key once `map` is full: drops the `stale` entries, then
+/// the oldest by `age` until it's half full, so sweeping the whole map happens
+/// at most once every `MAX_TRACKED / 2` new keys however fast keys churn
+fn make_room<V, A: Ord>(map: &mut HashMap<String, V>, stale: impl Fn(&V) -> bool, age: impl Fn(&V) -> A) {
+    if map.len() < MAX_TRACKED {
+        return;
+    }
+    map.retain(|_, val| !stale(val));
+
+    let keep = MAX_TRACKED / 2;
+    if map.len() > keep {
+        let mut by_age: Vec<(A, String)> = map.iter().map(|(key, val)| (age(val), key.clone())).collect();
+        let excess = by_age.len() - keep;
+        by_age.select_nth_unstable_by(excess - 1, |a, b| a.0.cmp(&b.0));
+        for (_, key) in &by_age[..excess] {
+            map.remove(key);
+        }
+    }
+}
+
+/// Limits on the sign in & sign up routes, so keys & passwords
+/// can't be guessed at as fast as the server answers
+#[derive(Debug)]
+pub struct AuthLimits {
+    per_ip: RateLimiter,
+    per_account: RateLimiter,
+    lockouts: Lockouts,
+    /// Whether to take the client's ip from `X-Forwarded-For`, only
+    /// safe behind a proxy that sets it
+    trust_forwarded_for: bool,
+}
+
+impl AuthLimits {
+    pub fn new(config: &RateLimitConfig, trust_forwarded_for: bool) -> Self {
+        AuthLimits {
+            per_ip: RateLimiter::per_minute(config.auth_ip_per_minute),
+            per_account: RateLimiter::per_minute(config.auth_account_per_minute),
+            lockouts: Lockouts::new(config.auth_lockout_after),
+            trust_forwarded_for,
+        }
+    }
+
+    /// Checks the client, & the account it's signing in to if known, are
+    /// under their limits & the account isn't locked out
+    pub fn check(&self, req: &HttpRequest, account: Option<&str>) -> Result<(), EchoError> {
+        self.per_ip.check(&client_ip(req, self.trust_forwarded_for)).map_err(EchoError::RateLimited)?;
+        if let Some(account) = account {
+            self.lockouts.check(account).map_err(EchoError::RateLimited)?;
+            self.per_account.check(account).map_err(EchoError::RateLimited)?;
+        }
+        Ok(())
+    }
+
+    /// Counts a sign in towards locking the account out, or clears
+    /// its failures once it's signed in to
+    pub fn record<T>(&self, account: Option<&str>, result: &Result<T, EchoError>) {
+        match (account, result) {
+            (Some(account), Ok(_)) => self.lockouts.record_success(account),
+            (Some(account), Err(EchoError::Unauthorized)) => self.lockouts.record_failure(account),
+            _ => {}
+        }
+    }
+}
+
+/// Scopes with their own request quotas
+#[derive(Clone, Copy, Debug)]
+pub enum Scope {
+    User,
+    Posts,
+    AuthActions,
+}
+
+/// Request quotas for the api scopes, by client ip & by signed in user
+#[derive(Debug)]
+pub struct ApiLimits {
+    user: RateLimiter,
+    posts: RateLimiter,
+    auth_actions: RateLimiter,
+    trust_forwarded_for: bool,
+}
+
+impl ApiLimits {
+    pub fn new(config: &RateLimitConfig, trust_forwarded_for: bool) -> Self {
+        ApiLimits {
+            user: RateLimiter::per_minute(config.user_per_minute),
+            posts: RateLimiter::per_minute(config.posts_per_minute),
+            auth_actions: RateLimiter::per_minute(config.auth_actions_per_minute),
+            trust_forwarded_for,
+        }
+    }
+
+    fn limiter(&self, scope: Scope) -> &RateLimiter {
+        match scope {
+            Scope::User => &self.user,
+            Scope::Posts => &self.posts,
+            Scope::AuthActions => &self.auth_actions,
+        }
+    }
+}
+
+pub async fn limit_user(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    enforce(Scope::User, req, next).await
+}
+
+pub async fn limit_posts(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    enforce(Scope::Posts, req, next).await
+}
+
+/// Goes inside the auth middleware, so the signed in user has a quota too
+pub async fn limit_auth_actions(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    enforce(Scope::AuthActions, req, next).await
+}
+
+/// Takes the request out of the scope's quotas for the client's ip & the
+/// signed in user, responding with `429` once either's used up. Responses
+/// say what's left of the one with less left with `RateLimit-*` headers.
+async fn enforce<B: MessageBody + 'static>(
+    scope: Scope,
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let state = req.app_data::<Data<AppState>>().context("app state is missing").map_err(EchoError::from)?.clone();
+    let limits = &state.api_limits;
+
+    // Signed in requests count against the client's ip too, so signing
+    // up more accounts doesn't get anyone more requests
+    let limiter = limits.limiter(scope);
+    let ip = client_ip(req.request(), limits.trust_forwarded_for);
+    let user_id = req.extensions().get::<TokenClaims>().map(|claims| claims.id);
+    let mut keys = vec![format!("ip:{}", ip)];
+    keys.extend(user_id.map(|user_id| format!("user:{}", user_id)));
+    let quota = limiter.take_all(&keys.iter().map(String::as_str).collect::<Vec<_>>());
+
+    let mut res = match quota.retry_after {
+        Some(retry_after) => {
+            log::debug!("rate limited: scope={:?} ip={} user_id={:?}", scope, ip, user_id);
+            let (req, _) = req.into_parts();
+            let res = HttpResponse::from_error(EchoError::RateLimited(retry_after));
+            ServiceResponse::new(req, res).map_into_right_body()
+        }
+        None => next.call(req).await?.map_into_left_body(),
+    };
+
+    let headers = res.headers_mut();
+    headers.insert(HeaderName::from_static("ratelimit-limit"), HeaderValue::from(quota.limit));
+    headers.insert(HeaderName::from_static("ratelimit-remaining"), HeaderValue::from(quota.remaining));
+    headers.insert(HeaderName::from_static("ratelimit-reset"), HeaderValue::from(quota.reset.as_secs_f64().ceil() as u64));
+    Ok(res)
+}
+
+/// The client's ip, or the proxy's when not trusting it to forward the client's
+pub fn client_ip(req: &HttpRequest, trust_forwarded_for: bool) -> String {
+    let conn_info = req.connection_info();
+    let ip = match trust_forwarded_for {
+        true => conn_info.realip_remote_addr().map(|addr| match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => addr.to_string(),
+        }),
+        false => req.peer_addr().map(|addr| addr.ip().to_string()),
+    };
+    ip.unwrap_or_else(|| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, error::negotiate_error_response};
+    use actix_web::{
+        dev::ServiceFactory,
+        middleware::{from_fn, ErrorHandlers},
+        test::{call_service, init_service, read_body_json, TestRequest},
+        web, App,
+    };
+    use uuid::Uuid;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn buckets_refill_at_capacity_a_minute() {
+        let limiter = RateLimiter::per_minute(3);
+        let start = Instant::now();
+
+        for remaining in [2, 1, 0] {
+            let quota = limiter.take_all_at(&["ip:1"], start);
+            assert_eq!((quota.limit, quota.remaining, quota.retry_after), (3, remaining, None));
+        }
+        let quota = limiter.take_all_at(&["ip:1"], start);
+        assert_eq!(quota.retry_after, Some(20 * SEC));
+        assert_eq!(quota.reset, 60 * SEC);
+
+        // Other keys have their own buckets
+        assert!(limiter.take_all_at(&["ip:2"], start).retry_after.is_none());
+
+        // A token every 20s, never more than the capacity
+        assert_eq!(limiter.take_all_at(&["ip:1"], start + 10 * SEC).retry_after, Some(10 * SEC));
+        assert!(limiter.take_all_at(&["ip:1"], start + 20 * SEC).retry_after.is_none());
+        assert!(limiter.take_all_at(&["ip:1"], start + 30 * SEC).retry_after.is_some());
+        let quota = limiter.take_all_at(&["ip:1"], start + 3600 * SEC);
+        assert_eq!((quota.remaining, quota.reset, quota.retry_after), (2, 20 * SEC, None));
+    }
+
+    #[test]
+    fn going_over_one_quota_spares_the_others() {
+        let limiter = RateLimiter::per_minute(2);
+        let start = Instant::now();
+
+        limiter.take_all_at(&["ip:1"], start);
+        limiter.take_all_at(&["ip:1"], start);
+        let quota = limiter.take_all_at(&["ip:1", "user:alice"], start);
+        assert_eq!((quota.remaining, quota.retry_after), (0, Some(30 * SEC)));
+
+        // Alice's request didn't go through, so it didn't count against her
+        let quota = limiter.take_all_at(&["ip:2", "user:alice"], start);
+        assert_eq!((quota.remaining, quota.reset, quota.retry_after), (1, 30 * SEC, None));
+        let quota = limiter.take_all_at(&["ip:2", "user:alice"], start);
+        assert_eq!((quota.remaining, quota.reset, quota.retry_after), (0, 60 * SEC, None));
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_max() {
+        let lockouts = Lockouts::new(3);
+        let mut now = Instant::now();
+
+        lockouts.record_failure_at("alice", now);
+        lockouts.record_failure_at("alice", now);
+        assert_eq!(lockouts.check_at("alice", now), Ok(()));
+
+        for lockout in [30, 60, 120, 240, 480, 900, 900] {
+            lockouts.record_failure_at("alice", now);
+            assert_eq!(lockouts.check_at("alice", now), Err(lockout * SEC));
+            assert_eq!(lockouts.check_at("alice", now + (lockout - 1) * SEC), Err(SEC));
+            assert_eq!(lockouts.check_at("bob", now), Ok(()));
+            now += lockout * SEC;
+            assert_eq!(lockouts.check_at("alice", now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn signing_in_clears_failures() {
+        let lockouts = Lockouts::new(2);
+        let now = Instant::now();
+
+        lockouts.record_failure_at("alice", now);
+        lockouts.record_failure_at("alice", now);
+        assert!(lockouts.check_at("alice", now).is_err());
+
+        lockouts.record_success("alice");
+        assert_eq!(lockouts.check_at("alice", now), Ok(()));
+        lockouts.record_failure_at("alice", now);
+        assert_eq!(lockouts.check_at("alice", now), Ok(()));
+    }
+
+    #[test]
+    fn full_buckets_drop_quiet_keys_then_the_oldest() {
+        let limiter = RateLimiter::per_minute(60);
+        let start = Instant::now();
+        let tracked = || limiter.buckets.lock().unwrap().len();
+
+        // Half the keys go quiet long enough to refill
+        for i in 0..MAX_TRACKED / 2 {
+            limiter.take_all_at(&[&format!("ip:quiet-{}", i)], start);
+        }
+        let busy_at = start + Duration::from_millis(59_500);
+        for i in 0..MAX_TRACKED / 2 {
+            limiter.take_all_at(&[&format!("ip:busy-{}", i)], busy_at + i as u32 * Duration::from_micros(1));
+        }
+        assert_eq!(tracked(), MAX_TRACKED);
+
+        limiter.take_all_at(&["ip:new"], start + 60 * SEC);
+        assert_eq!(tracked(), MAX_TRACKED / 2 + 1);
+        assert!(!limiter.buckets.lock().unwrap().contains_key("ip:quiet-0"));
+
+        // None are quiet, so the least recently seen half goes
+        for i in 0..MAX_TRACKED / 2 - 1 {
+            limiter.take_all_at(&[&format!("ip:busier-{}", i)], start + 60 * SEC);
+        }
+        assert_eq!(tracked(), MAX_TRACKED);
+        limiter.take_all_at(&["ip:newer"], start + 60 * SEC);
+        assert_eq!(tracked(), MAX_TRACKED / 2 + 1);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("ip:busy-0"));
+        assert!(buckets.contains_key("ip:new") && buckets.contains_key("ip:busier-0"));
+    }
+
+    #[test]
+    fn full_lockouts_keep_the_longest_lockouts() {
+        let lockouts = Lockouts::new(1);
+        let start = Instant::now();
+
+        for i in 0..MAX_TRACKED {
+            lockouts.record_failure_at(&format!("user-{}", i), start + i as u32 * Duration::from_micros(1));
+        }
+        lockouts.record_failure_at("new", start + SEC);
+
+        assert_eq!(lockouts.failures.lock().unwrap().len(), MAX_TRACKED / 2 + 1);
+        assert_eq!(lockouts.check_at("user-0", start + SEC), Ok(()));
+        assert!(lockouts.check_at(&format!("user-{}", MAX_TRACKED - 1), start + SEC).is_err());
+        assert!(lockouts.check_at("new", start + SEC).is_err());
+    }
+
+    /// Signs the request in as the user in its `x-user-id` header, standing in for the auth middleware
+    async fn sign_in_as(
+        req: ServiceRequest,
+        next: Next<impl MessageBody + 'static>,
+    ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+        let user_id = req.headers().get("x-user-id").and_then(|id| id.to_str().ok()?.parse().ok());
+        if let Some(id) = user_id {
+            let claims = TokenClaims { id, role: Default::default(), exp: 0, iat: 0, jti: Uuid::new_v4() };
+            req.extensions_mut().insert(claims);
+        }
+        next.call(req).await
+    }
+
+    /// The scopes, limited the way the api's routes are
+    fn app(
+        config: RateLimitConfig,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = Error,
+            InitError = (),
+        >,
+    > {
+        let mut state = AppState::for_tests(&Config::for_tests());
+        state.api_limits = ApiLimits::new(&config, false);
+
+        App::new()
+            .wrap(ErrorHandlers::new().default_handler(negotiate_error_response))
+            .app_data(Data::new(state))
+            .service(web::scope("/api/user").wrap(from_fn(limit_user)).route("", web::get().to(HttpResponse::Ok)))
+            .service(web::scope("/api/posts").wrap(from_fn(limit_posts)).route("", web::get().to(HttpResponse::Ok)))
+            .service(
+                web::scope("/api/auth-actions")
+                    .wrap(from_fn(limit_auth_actions))
+                    .wrap(from_fn(sign_in_as))
+                    .route("", web::get().to(HttpResponse::Ok)),
+            )
+    }
+
+    fn header<B>(res: &ServiceResponse<B>, name: &str) -> String {
+        res.headers().get(name).map(|val| val.to_str().unwrap().to_string()).unwrap_or_default()
+    }
+
+    fn ratelimit_headers<B>(res: &ServiceResponse<B>) -> [String; 3] {
+        ["ratelimit-limit", "ratelimit-remaining", "ratelimit-reset"].map(|name| header(res, name))
+    }
+
+    #[actix_web::test]
+    async fn scopes_answer_429_once_their_quota_is_used_up() {
+        let config = RateLimitConfig { posts_per_minute: 2, user_per_minute: 5, ..Default::default() };
+        let app = init_service(app(config)).await;
+
+        let res = call_service(&app, TestRequest::get().uri("/api/posts").to_request()).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(ratelimit_headers(&res), ["2", "1", "30"]);
+
+        let res = call_service(&app, TestRequest::get().uri("/api/posts").to_request()).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(ratelimit_headers(&res), ["2", "0", "60"]);
+
+        let res = call_service(&app, TestRequest::get().uri("/api/posts").to_request()).await;
+        assert_eq!(res.status(), 429);
+        assert_eq!(header(&res, "retry-after"), "30");
+        assert_eq!(ratelimit_headers(&res), ["2", "0", "60"]);
+        assert_eq!(header(&res, "content-type"), "application/problem+json");
+        let problem: serde_json::Value = read_body_json(res).await;
+        assert_eq!(problem["status"], 429);
+
+        // Other scopes have their own quotas
+        let res = call_service(&app, TestRequest::get().uri("/api/user").to_request()).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(ratelimit_headers(&res), ["5", "4", "12"]);
+    }
+
+    fn as_user(user_id: Uuid, ip: &str) -> TestRequest {
+        TestRequest::get()
+            .uri("/api/auth-actions")
+            .insert_header(("x-user-id", user_id.to_string()))
+            .peer_addr(format!("{}:443", ip).parse().unwrap())
+    }
+
+    #[actix_web::test]
+    async fn signed_in_quotas_are_by_user_and_ip() {
+        let config = RateLimitConfig { auth_actions_per_minute: 1, ..Default::default() };
+        let app = init_service(app(config)).await;
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let res = call_service(&app, as_user(alice, "192.0.2.1").to_request()).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(ratelimit_headers(&res), ["1", "0", "60"]);
+
+        // Moving to another ip doesn't get alice more requests
+        let res = call_service(&app, as_user(alice, "192.0.2.2").to_request()).await;
+        assert_eq!(res.status(), 429);
+        assert_eq!(header(&res, "retry-after"), "60");
+
+        // Nor does signing in as someone else from the same ip
+        let res = call_service(&app, as_user(bob, "192.0.2.1").to_request()).await;
+        assert_eq!(res.status(), 429);
+        assert_eq!(header(&res, "retry-after"), "60");
+
+        let res = call_service(&app, as_user(bob, "192.0.2.3").to_request()).await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn headers_are_for_whichever_quota_has_less_left() {
+        let config = RateLimitConfig { auth_actions_per_minute: 3, ..Default::default() };
+        let app = init_service(app(config)).await;
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let res = call_service(&app, as_user(alice, "192.0.2.1").to_request()).await;
+        assert_eq!(ratelimit_headers(&res), ["3", "2", "20"]);
+        let res = call_service(&app, as_user(alice, "192.0.2.1").to_request()).await;
+        assert_eq!(ratelimit_headers(&res), ["3", "1", "40"]);
+
+        // The ip's quota is down to its last request, bob's isn't
+        let res = call_service(&app, as_user(bob, "192.0.2.1").to_request()).await;
+        assert_eq!(ratelimit_headers(&res), ["3", "0", "60"]);
+
+        // Elsewhere the ip has more left than bob
+        let res = call_service(&app, as_user(bob, "192.0.2.2").to_request()).await;
+        assert_eq!(ratelimit_headers(&res), ["3", "1", "40"]);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::{
+    auth,
+    error::EchoError,
+    routes::user::KeyResponse,
+    structs::{AppState, FieldError, Role, TokenClaims, ValidationErrors},
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    get, post,
+    middleware::Next,
+    web::{Data, Json, Path, Query, ReqData},
+    Error, HttpMessage, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::{result::Result, sync::atomic::Ordering};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+
+/// Lets only admins through, goes inside the auth middleware
+pub async fn require_admin<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let is_admin = req.extensions().get::<TokenClaims>().is_some_and(|claims| claims.role == Role::Admin);
+    if !is_admin {
+        return Ok(req.error_response(EchoError::Forbidden("admins only")).map_into_right_body());
+    }
+
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// A user as admins see them
+#[derive(Serialize, Debug, FromRow, ToSchema)]
+pub struct AdminUser {
+    id: Uuid,
+    username: String,
+    role: Role,
+    disabled_at: Option<DateTime<Utc>>,
+    has_password: bool,
+    has_key: bool,
+    passkeys: i64,
+    saved_posts: i64,
+}
+
+const ADMIN_USER_SELECT: &str = "SELECT
+        id,
+        username,
+        role,
+        disabled_at,
+        password_hash IS NOT NULL AS has_password,
+        (key_hash IS NOT NULL OR hash IS NOT NULL) AS has_key,
+        (SELECT count(*) FROM passkeys WHERE user_id = users.id) AS passkeys,
+        (SELECT count(DISTINCT post_id) FROM unnest(saved_posts) AS post_id) AS saved_posts
+    FROM users";
+
+async fn query_admin_user(pool: &PgPool, user_id: Uuid) -> Result<Option<AdminUser>, sqlx::Error> {
+    sqlx::query_as::<_, AdminUser>(&format!("{} WHERE id = $1", ADMIN_USER_SELECT))
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct UsersQuery {
+    /// Users to skip, by username
+    offset: Option<i64>,
+    /// Users to list, up to 200 (default 50)
+    limit: Option<i64>,
+}
+
+// List Users
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    tag = "admin",
+    params(UsersQuery),
+    responses(
+        (status = 200, description = "A page of users, by username", body = [AdminUser]),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 403, description = "The user isn't an admin"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("users")]
+/// Endpoint listing users a page at a time
+pub async fn list_users(state: Data<AppState>, query: Query<UsersQuery>) -> Result<HttpResponse, EchoError> {
+    let offset = query.offset.unwrap_or(0).max(0);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let users = sqlx::query_as::<_, AdminUser>(&format!(
+        "{} ORDER BY lower(username) OFFSET $1 LIMIT $2",
+        ADMIN_USER_SELECT
+    ))
+    .bind(offset)
+    .bind(limit)
+    .fetch_all(&state.db_pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(users))
+}
+
+// Disable User
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/disable",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The disabled user, their sessions & tokens stop working", body = AdminUser),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 403, description = "The user isn't an admin, or is disabling themselves"),
+        (status = 404, description = "No such user"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("users/{id}/disable")]
+/// Endpoint disabling a user, they're signed out & can't sign back in
+pub async fn disable_user(
+    state: Data<AppState>,
+    path: Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let admin = req_user.ok_or(EchoError::Unauthorized)?;
+    let user_id = path.into_inner();
+    if user_id == admin.id {
+        return Err(EchoError::Forbidden("admins can't disable themselves"));
+    }
+
+    let mut tx = state.db_pool.begin().await?;
+    let updated = sqlx::query("UPDATE users SET disabled_at = coalesce(disabled_at, now()) WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(EchoError::NotFound("user"));
+    }
+    // Access tokens are denied by the auth middleware, refresh tokens are revoked for good
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    log::info!("disabled user: user_id={} admin_id={}", user_id, admin.id);
+
+    let user = query_admin_user(&state.db_pool, user_id).await?.ok_or(EchoError::NotFound("user"))?;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+// Enable User
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/enable",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user, who can sign in again", body = AdminUser),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 403, description = "The user isn't an admin"),
+        (status = 404, description = "No such user"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("users/{id}/enable")]
+/// Endpoint undoing `disable_user`
+pub async fn enable_user(
+    state: Data<AppState>,
+    path: Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let admin = req_user.ok_or(EchoError::Unauthorized)?;
+    let user_id = path.into_inner();
+
+    sqlx::query("UPDATE users SET disabled_at = NULL WHERE id = $1")
+        .bind(user_id)
+        .execute(&state.db_pool)
+        .await?;
+    log::info!("enabled user: user_id={} admin_id={}", user_id, admin.id);
+
+    let user = query_admin_user(&state.db_pool, user_id).await?.ok_or(EchoError::NotFound("user"))?;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+// Reissue Key
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/key",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 200, description = "A new hash key for the user, their old key & sessions stop working", body = KeyResponse),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 403, description = "The user isn't an admin"),
+        (status = 404, description = "No such user"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("users/{id}/key")]
+/// Endpoint giving a user a new hash key, for owners of revoked legacy
+/// keys who have no other way to sign in. Hand it over only once they've
+/// shown the account is theirs.
+pub async fn reissue_key(
+    state: Data<AppState>,
+    path: Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let admin = req_user.ok_or(EchoError::Unauthorized)?;
+    let user_id = path.into_inner();
+
+    let key = auth::generate_key(user_id);
+    let key_hash = auth::hash_password(key.clone()).await?;
+
+    let mut tx = state.db_pool.begin().await?;
+    let updated = sqlx::query("UPDATE users SET key_hash = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(&key_hash)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(EchoError::NotFound("user"));
+    }
+    // Whoever had the account before it was handed back is signed out
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    log::info!("reissued key: user_id={} admin_id={}", user_id, admin.id);
+
+    Ok(HttpResponse::Ok().json(KeyResponse { key }))
+}
+
+/// Posts to purge, by id and/or by author
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct PurgePostsRequest {
+    /// Hacker news ids of the posts
+    #[serde(default)]
+    post_ids: Vec<i64>,
+    /// Every post by this author
+    author: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct PurgePostsResponse {
+    /// Ids of the purged posts
+    purged: Vec<i64>,
+}
+
+// Purge Posts
+#[utoipa::path(
+    post,
+    path = "/admin/posts/purge",
+    tag = "admin",
+    request_body = PurgePostsRequest,
+    responses(
+        (status = 200, description = "The purged posts, they're gone from everyone's saved posts too", body = PurgePostsResponse),
+        (status = 400, description = "Neither posts nor an author to purge", body = ValidationErrors),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 403, description = "The user isn't an admin"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("posts/purge")]
+/// Endpoint purging spam posts
+pub async fn purge_posts(
+    state: Data<AppState>,
+    payload: Json<PurgePostsRequest>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let admin = req_user.ok_or(EchoError::Unauthorized)?;
+    let payload = payload.into_inner();
+    let author = payload.author.as_deref().map(str::trim).filter(|author| !author.is_empty());
+    if payload.post_ids.is_empty() && author.is_none() {
+        let message = "post_ids or an author is required";
+        return Err(ValidationErrors { errors: vec![FieldError::new("post_ids", message)] }.into());
+    }
+
+    let mut tx = state.db_pool.begin().await?;
+    let purged = sqlx::query_scalar::<_, i64>(
+        "DELETE FROM posts
+        WHERE post_id = ANY($1) OR author = $2
+        RETURNING post_id",
+    )
+    .bind(&payload.post_ids)
+    .bind(author)
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query(
+        "UPDATE users
+        SET saved_posts = array(SELECT post_id FROM unnest(saved_posts) AS post_id WHERE post_id <> ALL($1))
+        WHERE saved_posts && $1",
+    )
+    .bind(&purged)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    log::info!("purged posts: count={} admin_id={}", purged.len(), admin.id);
+
+    Ok(HttpResponse::Ok().json(PurgePostsResponse { purged }))
+}
+
+/// How many of everything there is
+#[derive(Serialize, Debug, FromRow, ToSchema)]
+pub struct Stats {
+    users: i64,
+    admins: i64,
+    disabled_users: i64,
+    posts: i64,
+    /// Saves across all users
+    saved_posts: i64,
+    passkeys: i64,
+    /// Refresh tokens (& website sessions) that can still be used
+    active_sessions: i64,
+    /// Requests this server is handling, this one included
+    #[sqlx(skip)]
+    in_flight_requests: u32,
+}
+
+// System Stats
+#[utoipa::path(
+    get,
+    path = "/admin/stats",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Counts of users, posts, sessions & requests in flight", body = Stats),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 403, description = "The user isn't an admin"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("stats")]
+/// Endpoint for system stats
+pub async fn get_stats(state: Data<AppState>) -> Result<HttpResponse, EchoError> {
+    let mut stats = sqlx::query_as::<_, Stats>(
+        "SELECT
+            (SELECT count(*) FROM users) AS users,
+            (SELECT count(*) FROM users WHERE role = 'admin') AS admins,
+            (SELECT count(*) FROM users WHERE disabled_at IS NOT NULL) AS disabled_users,
+            (SELECT count(*) FROM posts) AS posts,
+            (SELECT coalesce(sum(cardinality(saved_posts)), 0)::BIGINT FROM users) AS saved_posts,
+            (SELECT count(*) FROM passkeys) AS passkeys,
+            (SELECT count(*) FROM refresh_tokens WHERE revoked_at IS NULL AND expires_at > now()) AS active_sessions",
+    )
+    .fetch_one(&state.db_pool)
+    .await?;
+    stats.in_flight_requests = state.in_flight_requests.load(Ordering::Relaxed);
+
+    Ok(HttpResponse::Ok().json(stats))
+}
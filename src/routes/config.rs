@@ -1,13 +1,22 @@
-use crate::routes;
-use actix_web::web;
+use crate::{error, rate_limit, routes};
+use actix_web::{middleware::from_fn, web};
 use actix_web_httpauth::
     middleware::HttpAuthentication;
 use routes::user::token_validator;
 
 
-/// Configures all the api routes
-pub fn configure_routes(cfg: &mut web::ServiceConfig) {
-    let bearer_middleware = HttpAuthentication::bearer(token_validator);
+/// Configures all the api routes, taking request bodies up to `max_payload_size` bytes
+pub fn configure_routes(cfg: &mut web::ServiceConfig, max_payload_size: usize) {
+    cfg.app_data(web::FormConfig::default().limit(max_payload_size).error_handler(error::form_error))
+        .app_data(web::JsonConfig::default().limit(max_payload_size).error_handler(error::json_error))
+        .app_data(web::PayloadConfig::new(max_payload_size));
+
+    cfg.service(
+        // Versioned api, same routes as below but always JSON
+        web::scope("/api/v1")
+            .service(routes::openapi::get_openapi_spec)
+            .configure(configure_api_routes)
+    );
 
     cfg.service( 
         web::scope("")
@@ -21,24 +30,73 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
             .service(routes::web::get_sign_in_html)
             .service(routes::web::get_saved_feed_html)
 
+            // Public keys for verifying tokens
+            .service(routes::user::get_jwks)
+
             // API Routes
-            .service(
-                // User routes
-                web::scope("/user")
-                    .service(routes::user::sign_up)
-                    .service(routes::user::sign_in),
-            )
-            .service(
-                // Post routes
-                web::scope("/posts")
-                    .service(routes::posts::get_feed)
-            )
-            .service(
-                // Post routes
-                web::scope("/auth-actions")
-                    .wrap(bearer_middleware)
-                    .service(routes::posts::get_saved_posts)
-                    .service(routes::posts::save) 
-            )
+            .configure(configure_api_routes)
+    );
+}
+
+/// Configures the api routes, shared by the website & `/api/v1`
+fn configure_api_routes(cfg: &mut web::ServiceConfig) {
+    // Bearer tokens, or the website's session cookie
+    let auth_middleware = HttpAuthentication::with_fn(token_validator);
+
+    cfg.service(
+        // User routes
+        web::scope("/user")
+            .wrap(from_fn(rate_limit::limit_user))
+            .service(routes::user::sign_up)
+            .service(routes::user::sign_in)
+            .service(routes::user::refresh)
+            .service(routes::user::username_available)
+            .service(routes::oidc::oidc_button)
+            .service(routes::oidc::oidc_login)
+            .service(routes::oidc::oidc_callback)
+            .service(routes::passkeys::passkey_sign_in_options)
+            .service(routes::passkeys::passkey_sign_in),
+    )
+    .service(
+        // Post routes
+        web::scope("/posts")
+            .wrap(from_fn(rate_limit::limit_posts))
+            .service(routes::posts::get_feed)
+            .service(routes::posts::get_feed_atom)
+            .service(routes::posts::get_feed_rss)
+            .service(routes::posts::get_feed_json)
+            .service(routes::posts::get_saved_feed_atom)
+    )
+    .service(
+        // Post routes
+        web::scope("/auth-actions")
+            // Quotas go inside the auth so they're by the signed in user
+            .wrap(from_fn(rate_limit::limit_auth_actions))
+            .wrap(auth_middleware)
+            .service(routes::posts::get_saved_posts)
+            .service(routes::posts::save) 
+            .service(routes::posts::create_feed_token)
+            .service(routes::posts::revoke_feed_token)
+            .service(routes::user::logout)
+            .service(routes::user::get_account)
+            .service(routes::user::change_username)
+            .service(routes::user::regenerate_key)
+            .service(routes::user::delete_account)
+            .service(routes::passkeys::passkey_registration_options)
+            .service(routes::passkeys::register_passkey)
+            .service(routes::passkeys::get_passkeys)
+            .service(routes::passkeys::delete_passkey)
+    )
+    .service(
+        // Admin routes, the role check goes inside the auth
+        web::scope("/admin")
+            .wrap(from_fn(routes::admin::require_admin))
+            .wrap(HttpAuthentication::with_fn(token_validator))
+            .service(routes::admin::list_users)
+            .service(routes::admin::disable_user)
+            .service(routes::admin::enable_user)
+            .service(routes::admin::reissue_key)
+            .service(routes::admin::purge_posts)
+            .service(routes::admin::get_stats)
     );
 }
@@ -1,5 +1,10 @@
 pub mod user;
+pub mod admin;
 pub mod posts;
 pub mod web;
 pub mod config;
+pub mod negotiate;
+pub mod oidc;
+pub mod passkeys;
+pub mod openapi;
 
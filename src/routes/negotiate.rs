@@ -0,0 +1,93 @@
+use crate::error::EchoError;
+use actix_web::{
+    mime,
+    http::header::{self, Accept, Header},
+    HttpRequest, HttpResponse,
+};
+use serde::Serialize;
+
+
+/// Whether the client prefers JSON over the default html fragments.
+///
+/// Everything under `/api/` is JSON. Elsewhere htmx & browsers send `*/*`
+/// (or html first), so only an `Accept` header that ranks `application/json`
+/// first switches over to JSON.
+pub fn wants_json(req: &HttpRequest) -> bool {
+    if req.path().starts_with("/api/") {
+        return true;
+    }
+
+    match Accept::parse(req) {
+        Ok(accept) => accept.preference().essence_str() == mime::APPLICATION_JSON.essence_str(),
+        Err(_) => false,
+    }
+}
+
+/// Responds with `data` serialized as JSON if the client asked for it,
+/// otherwise with the html fragment rendered by `html`.
+pub fn negotiate<T: Serialize>(
+    req: &HttpRequest,
+    data: &T,
+    html: impl FnOnce(&T) -> askama::Result<String>,
+) -> Result<HttpResponse, EchoError> {
+    let mut response = HttpResponse::Ok();
+    response.insert_header((header::VARY, "Accept"));
+
+    if wants_json(req) {
+        Ok(response.json(data))
+    } else {
+        Ok(response.content_type("text/html; charset=utf-8").body(html(data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routes::posts::Post, templates::PostCards};
+    use actix_web::{body::to_bytes, test::TestRequest};
+    use askama::Template;
+
+    fn request(path: &str, accept: Option<&str>) -> HttpRequest {
+        let req = TestRequest::get().uri(path);
+        match accept {
+            Some(accept) => req.insert_header((header::ACCEPT, accept)).to_http_request(),
+            None => req.to_http_request(),
+        }
+    }
+
+    #[test]
+    fn json_when_asked_for_first_or_under_api() {
+        for accept in ["application/json", "application/json, text/html;q=0.9", "text/html;q=0.5, application/json"] {
+            assert!(wants_json(&request("/posts", Some(accept))), "{}", accept);
+        }
+        for accept in [None, Some("text/html"), Some("*/*"), Some("text/html, */*"), Some("application/json;q=0.5, text/html"), Some("nonsense")] {
+            assert!(!wants_json(&request("/posts", accept)), "{:?}", accept);
+            assert!(wants_json(&request("/api/v1/posts", accept)), "{:?}", accept);
+        }
+    }
+
+    #[actix_web::test]
+    async fn responds_with_the_json_or_the_fragment() {
+        let post = Post {
+            id: String::from("8863"),
+            title: String::from("My YC app: Dropbox"),
+            author: String::from("dhouston"),
+            url: String::from("https://www.dropbox.com/"),
+            timestamp: String::from("1175714200"),
+        };
+        let render = |post: &Post| PostCards { posts: std::slice::from_ref(post) }.render();
+
+        let res = negotiate(&request("/api/v1/posts", None), &post, render).unwrap();
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        let json: serde_json::Value = serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(json["title"], "My YC app: Dropbox");
+
+        let res = negotiate(&request("/posts", Some("text/html, */*")), &post, render).unwrap();
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+        let html = to_bytes(res.into_body()).await.unwrap();
+        let html = std::str::from_utf8(&html).unwrap();
+        assert!(html.contains("My YC app: Dropbox") && html.contains("href='https://www.dropbox.com/'"), "{}", html);
+    }
+}
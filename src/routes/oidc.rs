@@ -0,0 +1,275 @@
+use crate::{
+    auth,
+    error::EchoError,
+    oidc::{IdTokenClaims, OidcProvider},
+    routes::{
+        negotiate::wants_json,
+        user::{
+            is_username_taken, issue_tokens, session_cookies, session_user, username_conflict, validate_username,
+            TokenResponse, MAX_USERNAME_LEN, SESSION_COOKIE,
+        },
+    },
+    structs::{AppState, FieldError, ValidationErrors},
+    templates,
+};
+use actix_web::{
+    cookie::{time::Duration as CookieDuration, Cookie, SameSite},
+    get,
+    http::header,
+    web::{Data, Query},
+    HttpRequest, HttpResponse,
+};
+use askama::Template;
+use serde::Deserialize;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+
+/// Cookie tying the provider's redirect back to the browser that started the sign in
+const OIDC_STATE_COOKIE: &str = "echo_oidc_state";
+/// How long the user has to sign in with the provider
+const OIDC_LOGIN_TTL_SECS: i64 = 10 * 60;
+
+fn provider(state: &AppState) -> Result<&OidcProvider, EchoError> {
+    state.oidc.as_ref().ok_or(EchoError::NotFound("OpenID Connect sign in"))
+}
+
+#[get("oidc/button")]
+/// Endpoint for the sign in page's provider button, empty when it's turned off
+pub async fn oidc_button(state: Data<AppState>) -> Result<HttpResponse, EchoError> {
+    match &state.oidc {
+        Some(_) => Ok(HttpResponse::Ok().body(templates::OidcButton.render()?)),
+        None => Ok(HttpResponse::Ok().finish()),
+    }
+}
+
+// OpenID Connect Sign In
+#[utoipa::path(
+    get,
+    path = "/user/oidc/login",
+    tag = "user",
+    responses(
+        (status = 302, description = "Redirect to the provider's sign in, which redirects back to `/user/oidc/callback`"),
+        (status = 404, description = "OpenID Connect sign in is turned off"),
+    ),
+)]
+#[get("oidc/login")]
+/// Endpoint starting an authorization code (with PKCE) sign in with the
+/// provider, signed in users link the provider to their account instead
+pub async fn oidc_login(state: Data<AppState>, req: HttpRequest) -> Result<HttpResponse, EchoError> {
+    let provider = provider(&state)?;
+
+    let link_user_id = match req.cookie(SESSION_COOKIE) {
+        Some(session) => session_user(&state.db_pool, session.value()).await?.map(|(user_id, _)| user_id),
+        None => None,
+    };
+
+    let login_state = auth::random_hex();
+    let nonce = auth::random_hex();
+    let code_verifier = auth::random_hex();
+
+    // Clear out sign ins that were never finished while we're here
+    sqlx::query("DELETE FROM oidc_logins WHERE expires_at <= now()")
+        .execute(&state.db_pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO oidc_logins
+            (state, code_verifier, nonce, link_user_id, expires_at)
+        VALUES
+            ($1, $2, $3, $4, now() + $5 * interval '1 second')",
+    )
+    .bind(&login_state)
+    .bind(&code_verifier)
+    .bind(&nonce)
+    .bind(link_user_id)
+    .bind(OIDC_LOGIN_TTL_SECS)
+    .execute(&state.db_pool)
+    .await?;
+
+    let url = provider.authorization_url(&login_state, &nonce, &auth::pkce_challenge(&code_verifier))?;
+
+    // Lax, since the provider redirecting back is a cross site navigation
+    let cookie = Cookie::build(OIDC_STATE_COOKIE, login_state)
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::seconds(OIDC_LOGIN_TTL_SECS))
+        .finish();
+
+    Ok(HttpResponse::Found().insert_header((header::LOCATION, url)).cookie(cookie).finish())
+}
+
+/// What the provider redirects back with
+#[derive(Deserialize, Debug)]
+pub struct OidcCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(FromRow)]
+struct OidcLogin {
+    code_verifier: String,
+    nonce: String,
+    link_user_id: Option<Uuid>,
+}
+
+// OpenID Connect Callback
+#[utoipa::path(
+    get,
+    path = "/user/oidc/callback",
+    tag = "user",
+    params(
+        ("code" = String, Query, description = "Authorization code from the provider"),
+        ("state" = String, Query, description = "State `/user/oidc/login` sent to the provider"),
+    ),
+    responses(
+        (status = 200, description = "Tokens for the signed in user", body = TokenResponse),
+        (status = 302, description = "The website's session cookies are set, redirect to the feed"),
+        (status = 401, description = "The sign in was denied, expired or doesn't belong to this browser"),
+    ),
+)]
+#[get("oidc/callback")]
+/// Endpoint the provider redirects back to, signs in (or up) the
+/// user the provider vouched for
+pub async fn oidc_callback(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: Query<OidcCallback>,
+) -> Result<HttpResponse, EchoError> {
+    let provider = provider(&state)?;
+    let query = query.into_inner();
+
+    if let Some(error) = query.error {
+        log::debug!("oidc sign in denied: error={}", error);
+        return Err(EchoError::Unauthorized);
+    }
+    let (code, login_state) = match (query.code, query.state) {
+        (Some(code), Some(login_state)) => (code, login_state),
+        _ => {
+            let errors = vec![FieldError::new("code", "code & state are required")];
+            return Err(ValidationErrors { errors }.into());
+        }
+    };
+
+    if !is_same_browser(&req, &login_state) {
+        return Err(EchoError::Unauthorized);
+    }
+    let login = sqlx::query_as::<_, OidcLogin>(
+        "DELETE FROM oidc_logins
+        WHERE state = $1 AND expires_at > now()
+        RETURNING code_verifier, nonce, link_user_id",
+    )
+    .bind(&login_state)
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or(EchoError::Unauthorized)?;
+
+    let claims = provider.exchange_code(&code, &login.code_verifier, &login.nonce).await?;
+    let user_id = link_or_create_user(&state, &provider.issuer, &claims, login.link_user_id).await?;
+    let (token, refresh_token) = issue_tokens(&state, user_id).await?;
+
+    if wants_json(&req) {
+        return Ok(HttpResponse::Ok().json(TokenResponse { token, refresh_token }));
+    }
+
+    let (session, csrf) = session_cookies(refresh_token);
+    let mut state_cookie = Cookie::build(OIDC_STATE_COOKIE, "").path("/").finish();
+    state_cookie.make_removal();
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, "/"))
+        .cookie(session)
+        .cookie(csrf)
+        .cookie(state_cookie)
+        .finish())
+}
+
+/// The state has to come back to the same browser that started the sign in
+fn is_same_browser(req: &HttpRequest, login_state: &str) -> bool {
+    req.cookie(OIDC_STATE_COOKIE).is_some_and(|cookie| cookie.value() == login_state)
+}
+
+/// The user signed in with the provider's subject, linking it to
+/// `link_user_id` or a new user the first time it's seen
+async fn link_or_create_user(
+    state: &AppState,
+    issuer: &str,
+    claims: &IdTokenClaims,
+    link_user_id: Option<Uuid>,
+) -> Result<Uuid, EchoError> {
+    let linked_user_id = sqlx::query_scalar::<_, Uuid>(
+        "SELECT user_id FROM oidc_identities
+        WHERE issuer = $1 AND subject = $2",
+    )
+    .bind(issuer)
+    .bind(&claims.sub)
+    .fetch_optional(&state.db_pool)
+    .await?;
+
+    match (linked_user_id, link_user_id) {
+        (Some(linked_user_id), Some(link_user_id)) if linked_user_id != link_user_id => {
+            Err(EchoError::Forbidden("this provider account is linked to another user"))
+        }
+        (Some(linked_user_id), _) => Ok(linked_user_id),
+        (None, link_user_id) => {
+            let mut tx = state.db_pool.begin().await?;
+
+            let user_id = match link_user_id {
+                Some(link_user_id) => link_user_id,
+                None => {
+                    let user_id = Uuid::new_v4();
+                    // Usernames are the provider's, unless it's one we wouldn't
+                    // let someone pick or someone here already has it
+                    let suffix = &user_id.simple().to_string()[..8];
+                    let username = match claims.preferred_username.as_deref().map(validate_username) {
+                        Some(Ok(username)) => username.to_string(),
+                        _ => format!("user-{}", suffix),
+                    };
+                    let username = match is_username_taken(&mut *tx, &username, None).await? {
+                        true => {
+                            let base: String = username.chars().take(MAX_USERNAME_LEN - suffix.len() - 1).collect();
+                            format!("{}-{}", base, suffix)
+                        }
+                        false => username,
+                    };
+
+                    sqlx::query("INSERT INTO users (id, username) VALUES ($1, $2)")
+                        .bind(user_id)
+                        .bind(&username)
+                        .execute(&mut *tx)
+                        .await
+                        .map_err(username_conflict)?;
+                    user_id
+                }
+            };
+
+            sqlx::query("INSERT INTO oidc_identities (issuer, subject, user_id) VALUES ($1, $2, $3)")
+                .bind(issuer)
+                .bind(&claims.sub)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            log::info!("linked oidc identity: user_id={} issuer={}", user_id, issuer);
+            Ok(user_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn state_has_to_match_the_browsers_cookie() {
+        let with_cookie = |val: &str| TestRequest::default().cookie(Cookie::new(OIDC_STATE_COOKIE, val.to_string())).to_http_request();
+
+        assert!(is_same_browser(&with_cookie("abc123"), "abc123"));
+        assert!(!is_same_browser(&with_cookie("abc123"), "abc124"));
+        assert!(!is_same_browser(&with_cookie(""), "abc123"));
+        assert!(!is_same_browser(&TestRequest::default().to_http_request(), "abc123"));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{routes, structs};
+use actix_web::{get, HttpResponse};
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+
+/// OpenAPI document describing the `/api/v1` routes
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Echo API", description = "Curated Content Feed"),
+    servers((url = "/api/v1")),
+    paths(
+        routes::user::sign_up,
+        routes::user::sign_in,
+        routes::user::refresh,
+        routes::user::username_available,
+        routes::user::logout,
+        routes::oidc::oidc_login,
+        routes::oidc::oidc_callback,
+        routes::passkeys::passkey_sign_in_options,
+        routes::passkeys::passkey_sign_in,
+        routes::posts::get_feed,
+        routes::posts::get_feed_atom,
+        routes::posts::get_feed_rss,
+        routes::posts::get_feed_json,
+        routes::posts::get_saved_feed_atom,
+        routes::posts::get_saved_posts,
+        routes::posts::save,
+        routes::posts::create_feed_token,
+        routes::posts::revoke_feed_token,
+        routes::user::get_account,
+        routes::user::change_username,
+        routes::user::regenerate_key,
+        routes::user::delete_account,
+        routes::passkeys::passkey_registration_options,
+        routes::passkeys::register_passkey,
+        routes::passkeys::get_passkeys,
+        routes::passkeys::delete_passkey,
+        routes::admin::list_users,
+        routes::admin::disable_user,
+        routes::admin::enable_user,
+        routes::admin::reissue_key,
+        routes::admin::purge_posts,
+        routes::admin::get_stats,
+    ),
+    components(schemas(
+        routes::posts::Post,
+        routes::posts::FeedTokenResponse,
+        routes::posts::RevokeFeedTokenResponse,
+        routes::posts::SaveRequest,
+        routes::user::SignUpForm,
+        routes::user::SignUpPayload,
+        routes::user::SignInForm,
+        routes::user::RefreshForm,
+        routes::user::LogoutForm,
+        routes::user::TokenResponse,
+        routes::user::Account,
+        routes::user::ChangeUsernameForm,
+        routes::user::KeyResponse,
+        routes::user::UsernameAvailability,
+        routes::passkeys::RegistrationOptions,
+        routes::passkeys::RelyingPartyEntity,
+        routes::passkeys::UserEntity,
+        routes::passkeys::CredentialParameters,
+        routes::passkeys::CredentialDescriptor,
+        routes::passkeys::AuthenticatorSelection,
+        routes::passkeys::SignInOptions,
+        routes::passkeys::RegisterPasskeyRequest,
+        routes::passkeys::PasskeySignInRequest,
+        routes::passkeys::Passkey,
+        routes::admin::AdminUser,
+        routes::admin::PurgePostsRequest,
+        routes::admin::PurgePostsResponse,
+        routes::admin::Stats,
+        structs::Role,
+        structs::FieldError,
+        structs::ValidationErrors,
+    )),
+    modifiers(&BearerSecurity),
+    tags(
+        (name = "user", description = "Account sign up, sign in & token refresh"),
+        (name = "posts", description = "The public feed"),
+        (name = "auth-actions", description = "Actions requiring a signed in user"),
+        (name = "admin", description = "Moderation, requiring an admin"),
+    ),
+)]
+pub struct ApiDoc;
+
+/// Registers the bearer token scheme the `auth-actions` routes require
+struct BearerSecurity;
+impl Modify for BearerSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+        }
+    }
+}
+
+#[get("/openapi.json")]
+/// Endpoint serving the OpenAPI document for the `/api/v1` routes
+pub async fn get_openapi_spec() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
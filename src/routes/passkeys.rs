@@ -0,0 +1,460 @@
+use crate::{
+    auth,
+    error::EchoError,
+    routes::{negotiate::wants_json, user::{issue_tokens, session_cookies, TokenResponse}},
+    structs::{AppState, FieldError, TokenClaims, ValidationErrors},
+    webauthn,
+};
+use actix_web::{
+    delete, get, post,
+    web::{Data, Json, Path, ReqData},
+    HttpRequest, HttpResponse,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::result::Result;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+
+/// Options for `navigator.credentials.create()`, binary fields are base64url
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationOptions {
+    challenge: String,
+    rp: RelyingPartyEntity,
+    user: UserEntity,
+    pub_key_cred_params: Vec<CredentialParameters>,
+    exclude_credentials: Vec<CredentialDescriptor>,
+    authenticator_selection: AuthenticatorSelection,
+    attestation: &'static str,
+    timeout: i64,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RelyingPartyEntity {
+    id: String,
+    name: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    credential_type: &'static str,
+    alg: i32,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    credential_type: &'static str,
+    id: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    resident_key: &'static str,
+    user_verification: &'static str,
+}
+
+/// Options for `navigator.credentials.get()`, binary fields are base64url
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SignInOptions {
+    challenge: String,
+    rp_id: String,
+    user_verification: &'static str,
+    timeout: i64,
+}
+
+/// A new credential, from `navigator.credentials.create()`, binary fields are base64url
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct RegisterPasskeyRequest {
+    /// Credential id
+    id: String,
+    client_data_json: String,
+    /// `response.getAuthenticatorData()`
+    authenticator_data: String,
+    /// `response.getPublicKey()`, SPKI DER
+    public_key: String,
+    /// `response.getPublicKeyAlgorithm()`, a COSE algorithm
+    public_key_algorithm: i32,
+    /// What to call the passkey, like the device it's on
+    name: Option<String>,
+}
+
+/// An assertion, from `navigator.credentials.get()`, binary fields are base64url
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct PasskeySignInRequest {
+    /// Credential id
+    id: String,
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+}
+
+/// A registered passkey
+#[derive(Serialize, Debug, FromRow, ToSchema)]
+pub struct Passkey {
+    /// Credential id
+    #[sqlx(rename = "credential_id")]
+    id: String,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(FromRow)]
+struct StoredPasskey {
+    user_id: Uuid,
+    public_key: Vec<u8>,
+    algorithm: i32,
+    sign_count: i64,
+}
+
+const CHALLENGE_TIMEOUT_MS: i64 = webauthn::CHALLENGE_TTL_SECS * 1000;
+
+fn decode(field: &'static str, value: &str) -> Result<Vec<u8>, ValidationErrors> {
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|_| ValidationErrors { errors: vec![FieldError::new(field, "must be unpadded base64url")] })
+}
+
+/// Stores a new challenge, for the user registering a passkey or `None` to sign in
+async fn new_challenge(pool: &PgPool, user_id: Option<Uuid>) -> Result<String, sqlx::Error> {
+    let challenge = auth::random_base64url();
+
+    // Clear out ceremonies that were never finished while we're here
+    sqlx::query("DELETE FROM webauthn_challenges WHERE expires_at <= now()")
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO webauthn_challenges
+            (challenge, user_id, expires_at)
+        VALUES
+            ($1, $2, now() + $3 * interval '1 second')",
+    )
+    .bind(&challenge)
+    .bind(user_id)
+    .bind(webauthn::CHALLENGE_TTL_SECS)
+    .execute(pool)
+    .await?;
+
+    Ok(challenge)
+}
+
+/// Uses up a challenge, whether it was ours to use
+async fn take_challenge(pool: &PgPool, challenge: &str, user_id: Option<Uuid>) -> Result<bool, sqlx::Error> {
+    let taken = sqlx::query(
+        "DELETE FROM webauthn_challenges
+        WHERE challenge = $1 AND user_id IS NOT DISTINCT FROM $2 AND expires_at > now()",
+    )
+    .bind(challenge)
+    .bind(user_id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(taken == 1)
+}
+
+// Passkey Registration Options
+#[utoipa::path(
+    post,
+    path = "/auth-actions/passkeys/options",
+    tag = "auth-actions",
+    responses(
+        (status = 200, description = "Options for `navigator.credentials.create()`", body = RegistrationOptions),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/passkeys/options")]
+/// Endpoint starting the registration of a passkey for the signed in user
+pub async fn passkey_registration_options(
+    state: Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let user = req_user.ok_or(EchoError::Unauthorized)?;
+
+    let username = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE id = $1")
+        .bind(user.id)
+        .fetch_optional(&state.db_pool)
+        .await?
+        .ok_or(EchoError::Unauthorized)?;
+    // Each authenticator only needs registering once
+    let registered = sqlx::query_scalar::<_, String>("SELECT credential_id FROM passkeys WHERE user_id = $1")
+        .bind(user.id)
+        .fetch_all(&state.db_pool)
+        .await?;
+
+    let relying_party = &state.relying_party;
+    let options = RegistrationOptions {
+        challenge: new_challenge(&state.db_pool, Some(user.id)).await?,
+        rp: RelyingPartyEntity { id: relying_party.id.clone(), name: relying_party.name.clone() },
+        user: UserEntity {
+            id: URL_SAFE_NO_PAD.encode(user.id.as_bytes()),
+            name: username.clone(),
+            display_name: username,
+        },
+        pub_key_cred_params: webauthn::ALGORITHMS
+            .iter()
+            .map(|&alg| CredentialParameters { credential_type: "public-key", alg })
+            .collect(),
+        exclude_credentials: registered
+            .into_iter()
+            .map(|id| CredentialDescriptor { credential_type: "public-key", id })
+            .collect(),
+        // Discoverable, so signing in doesn't need a username
+        authenticator_selection: AuthenticatorSelection { resident_key: "required", user_verification: "preferred" },
+        attestation: "none",
+        timeout: CHALLENGE_TIMEOUT_MS,
+    };
+
+    Ok(HttpResponse::Ok().json(options))
+}
+
+// Register Passkey
+#[utoipa::path(
+    post,
+    path = "/auth-actions/passkeys",
+    tag = "auth-actions",
+    request_body = RegisterPasskeyRequest,
+    responses(
+        (status = 201, description = "The registered passkey", body = Passkey),
+        (status = 400, description = "The credential didn't check out", body = ValidationErrors),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/passkeys")]
+/// Endpoint registering a passkey created with `passkey_registration_options`
+pub async fn register_passkey(
+    state: Data<AppState>,
+    payload: Json<RegisterPasskeyRequest>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let user = req_user.ok_or(EchoError::Unauthorized)?;
+    let payload = payload.into_inner();
+    let invalid = |message: &str| EchoError::from(ValidationErrors { errors: vec![FieldError::new("credential", message)] });
+
+    let credential_id = decode("id", &payload.id)?;
+    let client_data_json = decode("client_data_json", &payload.client_data_json)?;
+    let auth_data = decode("authenticator_data", &payload.authenticator_data)?;
+    let public_key = decode("public_key", &payload.public_key)?;
+
+    let relying_party = &state.relying_party;
+    let challenge = relying_party.verify_client_data(&client_data_json, "webauthn.create").map_err(invalid)?;
+    if !take_challenge(&state.db_pool, &challenge, Some(user.id)).await? {
+        return Err(invalid("challenge is unknown or expired"));
+    }
+    let auth_data = relying_party.verify_authenticator_data(&auth_data).map_err(invalid)?;
+    if auth_data.credential_id != Some(credential_id.as_slice()) {
+        return Err(invalid("authenticator data is for another credential"));
+    }
+    if !webauthn::is_usable_key(payload.public_key_algorithm, &public_key) {
+        return Err(invalid("unsupported public key"));
+    }
+
+    let name = payload.name.as_deref().map(str::trim).filter(|name| !name.is_empty()).unwrap_or("Passkey");
+    let passkey = sqlx::query_as::<_, Passkey>(
+        "INSERT INTO passkeys
+            (credential_id, user_id, name, public_key, algorithm, sign_count)
+        VALUES
+            ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (credential_id) DO NOTHING
+        RETURNING credential_id, name, created_at, last_used_at",
+    )
+    .bind(&payload.id)
+    .bind(user.id)
+    .bind(name)
+    .bind(&public_key)
+    .bind(payload.public_key_algorithm)
+    .bind(i64::from(auth_data.sign_count))
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or_else(|| invalid("is already registered"))?;
+
+    Ok(HttpResponse::Created().json(passkey))
+}
+
+// List Passkeys
+#[utoipa::path(
+    get,
+    path = "/auth-actions/passkeys",
+    tag = "auth-actions",
+    responses(
+        (status = 200, description = "The signed in user's passkeys", body = [Passkey]),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/passkeys")]
+/// Endpoint listing the signed in user's passkeys
+pub async fn get_passkeys(
+    state: Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let user = req_user.ok_or(EchoError::Unauthorized)?;
+
+    let passkeys = sqlx::query_as::<_, Passkey>(
+        "SELECT credential_id, name, created_at, last_used_at FROM passkeys
+        WHERE user_id = $1
+        ORDER BY created_at",
+    )
+    .bind(user.id)
+    .fetch_all(&state.db_pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(passkeys))
+}
+
+// Delete Passkey
+#[utoipa::path(
+    delete,
+    path = "/auth-actions/passkeys/{id}",
+    tag = "auth-actions",
+    params(("id" = String, Path, description = "Credential id")),
+    responses(
+        (status = 204, description = "The passkey was removed"),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 404, description = "The user has no such passkey"),
+    ),
+    security(("bearer" = [])),
+)]
+#[delete("/passkeys/{id}")]
+/// Endpoint removing one of the signed in user's passkeys
+pub async fn delete_passkey(
+    state: Data<AppState>,
+    path: Path<String>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let user = req_user.ok_or(EchoError::Unauthorized)?;
+
+    let deleted = sqlx::query("DELETE FROM passkeys WHERE credential_id = $1 AND user_id = $2")
+        .bind(path.into_inner())
+        .bind(user.id)
+        .execute(&state.db_pool)
+        .await?
+        .rows_affected();
+
+    match deleted {
+        0 => Err(EchoError::NotFound("passkey")),
+        _ => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+// Passkey Sign In Options
+#[utoipa::path(
+    post,
+    path = "/user/passkey/options",
+    tag = "user",
+    responses(
+        (status = 200, description = "Options for `navigator.credentials.get()`", body = SignInOptions),
+    ),
+)]
+#[post("passkey/options")]
+/// Endpoint starting a passkey sign in
+pub async fn passkey_sign_in_options(state: Data<AppState>) -> Result<HttpResponse, EchoError> {
+    let options = SignInOptions {
+        challenge: new_challenge(&state.db_pool, None).await?,
+        rp_id: state.relying_party.id.clone(),
+        user_verification: "preferred",
+        timeout: CHALLENGE_TIMEOUT_MS,
+    };
+
+    Ok(HttpResponse::Ok().json(options))
+}
+
+// Passkey Sign In
+#[utoipa::path(
+    post,
+    path = "/user/passkey/sign-in",
+    tag = "user",
+    request_body = PasskeySignInRequest,
+    responses(
+        (status = 200, description = "A bearer token for the `auth-actions` routes", body = TokenResponse),
+        (status = 204, description = "The website's session cookies are set"),
+        (status = 401, description = "The assertion didn't check out"),
+    ),
+)]
+#[post("passkey/sign-in")]
+/// Endpoint signing in with a passkey assertion for a `passkey_sign_in_options` challenge
+pub async fn passkey_sign_in(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: Json<PasskeySignInRequest>,
+) -> Result<HttpResponse, EchoError> {
+    state.auth_limits.check(&req, None)?;
+
+    let payload = payload.into_inner();
+    let denied = |reason: &str| {
+        log::debug!("passkey sign in denied: reason={}", reason);
+        EchoError::Unauthorized
+    };
+
+    let client_data_json = decode("client_data_json", &payload.client_data_json)?;
+    let auth_data = decode("authenticator_data", &payload.authenticator_data)?;
+    let signature = decode("signature", &payload.signature)?;
+
+    let relying_party = &state.relying_party;
+    let challenge = relying_party.verify_client_data(&client_data_json, "webauthn.get").map_err(denied)?;
+    if !take_challenge(&state.db_pool, &challenge, None).await? {
+        return Err(denied("challenge is unknown or expired"));
+    }
+    let parsed_auth_data = relying_party.verify_authenticator_data(&auth_data).map_err(denied)?;
+
+    let passkey = sqlx::query_as::<_, StoredPasskey>(
+        "SELECT user_id, public_key, algorithm, sign_count FROM passkeys
+        WHERE credential_id = $1",
+    )
+    .bind(&payload.id)
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or_else(|| denied("unknown credential"))?;
+
+    if !webauthn::verify_signature(passkey.algorithm, &passkey.public_key, &auth_data, &client_data_json, &signature) {
+        return Err(denied("bad signature"));
+    }
+    // Authenticators that count should only count up, otherwise the credential may've been cloned
+    let sign_count = i64::from(parsed_auth_data.sign_count);
+    if !webauthn::is_sign_count_valid(passkey.sign_count, parsed_auth_data.sign_count) {
+        log::warn!("passkey sign count went backwards: user_id={} credential_id={}", passkey.user_id, payload.id);
+        return Err(denied("sign count went backwards"));
+    }
+
+    // Checked again as it's stored, so concurrent sign ins with the same count can't both get in
+    let updated = sqlx::query(
+        "UPDATE passkeys SET sign_count = $2, last_used_at = now()
+        WHERE credential_id = $1 AND (sign_count < $2 OR ($2 = 0 AND sign_count = 0))",
+    )
+    .bind(&payload.id)
+    .bind(sign_count)
+    .execute(&state.db_pool)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        log::warn!("passkey sign count was already used: user_id={} credential_id={}", passkey.user_id, payload.id);
+        return Err(denied("sign count was already used"));
+    }
+
+    let (token, refresh_token) = issue_tokens(&state, passkey.user_id).await?;
+    if wants_json(&req) {
+        return Ok(HttpResponse::Ok().json(TokenResponse { token, refresh_token }));
+    }
+
+    // The website signs in with a session cookie instead of the tokens
+    let (session, csrf) = session_cookies(refresh_token);
+    Ok(HttpResponse::NoContent().cookie(session).cookie(csrf).finish())
+}
@@ -1,45 +1,141 @@
-use crate::{error::EchoError, structs::{AppState, TokenClaims}};
+use crate::{error::EchoError, feed, routes::negotiate::negotiate, structs::{AppState, FieldError, TokenClaims, ValidationErrors}, templates};
+use askama::Template;
 use actix_web::{
-    post, get,
+    post, get, delete,
     web::{Data, Json, Path, ReqData},
     HttpResponse, HttpRequest,
 };
-use sqlx::FromRow;
-use std::{result::Result, hash::{Hash, Hasher}, future::{self, Future}, collections::VecDeque, sync::Arc};
+use sqlx::{FromRow, PgPool};
+use std::{result::Result, hash::{Hash, Hasher}, collections::VecDeque, sync::Arc};
 use uuid::Uuid;
 use std::collections::hash_map::DefaultHasher;
 use serde::{Serialize, Deserialize};
-use reqwest::{Response, Client};
+use utoipa::ToSchema;
+use reqwest::{Client, Url};
 
-use futures::future::join_all;
-use std::error::Error;
 
-
-#[derive(Serialize, Deserialize, Hash, Debug, Clone)]
+#[derive(Serialize, Deserialize, Hash, Debug, Clone, ToSchema)]
 pub struct Post {
+    pub id: String,
+    pub title: String,
+    pub author: String,
+    pub url: String,
+    pub timestamp: String,
+} impl Post {
+    /// Link to the post's discussion on hacker news
+    pub fn permalink(&self) -> String {
+        format!("https://news.ycombinator.com/item?id={}", self.id)
+    }
+
+    /// The post's external url, only if it's a well formed http(s) url.
+    /// Anything else (`javascript:`, `data:`, garbage) is dropped so it
+    /// never ends up in an `href`, the url is client controlled on save.
+    pub fn external_url(&self) -> Option<String> {
+        match Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Some(url.into()),
+            _ => None,
+        }
+    }
+
+    /// Link to the post's content, falling back to the discussion
+    /// for posts without an external url (Ask HN, etc)
+    pub fn link(&self) -> String {
+        self.external_url().unwrap_or_else(|| self.permalink())
+    }
+}
+
+/// Payload for saving a post, the post's content is fetched
+/// from its source rather than trusted from the client.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SaveRequest {
+    /// Where the post is from, only `hn` (hacker news) for now
+    source: String,
+    /// Id of the post on its source
     id: String,
-    title: String,
-    author: String,
-    url: String,
-    timestamp: String,
 }
 
+/// Sources posts can be saved from
+#[derive(Debug)]
+enum Source {
+    HackerNews,
+}
+
+/// A save request that's been checked field by field
+#[derive(Debug)]
+struct SavePost {
+    source: Source,
+    id: i64,
+}
+impl TryFrom<SaveRequest> for SavePost {
+    type Error = ValidationErrors;
+
+    fn try_from(request: SaveRequest) -> Result<Self, Self::Error> {
+        let mut errors = Vec::new();
+
+        let source = match request.source.as_str() {
+            "hn" => Some(Source::HackerNews),
+            _ => {
+                errors.push(FieldError::new("source", "must be one of: hn"));
+                None
+            }
+        };
+
+        let id = match request.id.parse::<i64>() {
+            Ok(id) if id > 0 => Some(id),
+            _ => {
+                errors.push(FieldError::new("id", "must be a positive integer"));
+                None
+            }
+        };
+
+        match (source, id) {
+            (Some(source), Some(id)) => Ok(SavePost { source, id }),
+            _ => Err(ValidationErrors { errors }),
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth-actions/save",
+    tag = "auth-actions",
+    request_body = SaveRequest,
+    responses(
+        (status = 200, description = "The saved post, as it is on its source", body = Post),
+        (status = 400, description = "Invalid fields, or no such post on the source", body = ValidationErrors),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 502, description = "The source couldn't be reached"),
+    ),
+    security(("bearer" = [])),
+)]
 #[post("/save")]
 /// Endpoint for saving posts
 pub async fn save(
     state: Data<AppState>,
-    payload: Json<Post>,
+    req: HttpRequest,
+    payload: Json<SaveRequest>,
     req_user: Option<ReqData<TokenClaims>>
 ) -> Result<HttpResponse, EchoError> {
     // Consume Payload ownership
     match req_user {
         Some(user) => {
-            let post_to_save = payload.into_inner();
+            let save_post = SavePost::try_from(payload.into_inner())?;
+
+            // Fetch the canonical post from its source
+            let client = Client::new();
+            let fetched = match save_post.source {
+                Source::HackerNews => fetch_hn_post(save_post.id, &client).await,
+            };
+            let hn_post = fetched?.ok_or_else(|| ValidationErrors {
+                errors: vec![FieldError::new("id", "no such post on the source")],
+            })?;
+            let (post_id, post_time) = (hn_post.id, hn_post.time);
+            let post = hn_post.into_post();
 
             // Hash the content of the post struct excluding
             // the user_id, this way we replicate 
             let mut hasher = DefaultHasher::new();
-            post_to_save.hash(&mut hasher);
+            post.hash(&mut hasher);
             let post_hash = format!("{:#01x}",hasher.finish());
 
             // Create row in saved_posts table
@@ -50,39 +146,28 @@ pub async fn save(
             ON CONFLICT (hash) DO NOTHING
             ")
             .bind(&post_hash)
-            .bind(&post_to_save.id.parse::<i64>().unwrap())
-            .bind(&post_to_save.title)
-            .bind(&post_to_save.url)
-            .bind(&post_to_save.author)
-            .bind(&post_to_save.timestamp.parse::<i64>().unwrap())
+            .bind(post_id)
+            .bind(&post.title)
+            .bind(&post.url)
+            .bind(&post.author)
+            .bind(post_time)
             .execute(&state.db_pool)
             .await?;
 
             // Add the post_id to users saved posts
-            match sqlx::query("UPDATE users
+            sqlx::query("UPDATE users
                 SET saved_posts = saved_posts || $1
                 WHERE id = $2
             ")
-            .bind(&post_to_save.id.parse::<i64>().unwrap())
-            .bind(&user.id)
+            .bind(post_id)
+            .bind(user.id)
             .execute(&state.db_pool)
-            .await {
-                Ok(_) => {
-                    let updated_html = String::from("<button
-                      id='save-btn'
-                      type='submit'
-                      class='rounded-md bg-primary px-3.5 py-2.5 text-sm font-semibold text-white shadow-lg'
-                    >Saved</button>");
-                    Ok(HttpResponse::Ok().body(updated_html))
-                },
-                Err(e) => {
-                    println!("{:?}", e);
-                    Ok(HttpResponse::InternalServerError().body(""))
-                },
-            }
+            .await?;
+
+            negotiate(&req, &post, |_| templates::SavedButton.render())
 
                 }
-                None => Ok(HttpResponse::Unauthorized().body(""))
+                None => Err(EchoError::Unauthorized)
             }
 
 }
@@ -106,50 +191,209 @@ struct SavedPosts {
     }
 }
 
+/// Query all saved posts a user has
+async fn query_saved_posts(pool: &PgPool, user_id: &Uuid) -> Result<Vec<Post>, sqlx::Error> {
+    let saved_posts = sqlx::query_as::<_, SavedPosts>("SELECT post_id, title, url, author, timestamp 
+        FROM posts WHERE post_id = ANY(SELECT unnest(saved_posts) FROM users WHERE id = $1)
+    ")
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(saved_posts.into_iter().map(SavedPosts::into_post).collect())
+}
+
 // User Get Saved Posts
+#[utoipa::path(
+    post,
+    path = "/auth-actions/saved",
+    tag = "auth-actions",
+    responses(
+        (status = 200, description = "The user's saved posts", body = [Post]),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
 #[post("saved")]
 /// Endpoint for saving posts
 pub async fn get_saved_posts(
     state: Data<AppState>,
+    req: HttpRequest,
     req_user: Option<ReqData<TokenClaims>>,
 ) -> Result<HttpResponse, EchoError> {
     // Consume path value ownership
     match req_user {
         Some(user) => {
-            // Query all saved posts a user has
-            match sqlx::query_as::<_, SavedPosts>("SELECT post_id, title, url, author, timestamp 
-                FROM posts WHERE post_id = ANY(SELECT unnest(saved_posts) FROM users WHERE id = $1)
-            ")
-            .bind(&user.id)
-            .fetch_all(&state.db_pool)
-            .await {
-                Ok(saved_posts) => {
-                    let mut content_cards_html: Vec<String> = Vec::new();
-                    for saved_post in saved_posts.into_iter() {
-                        let post = saved_post.into_post();
-                        content_cards_html.push(create_post_html_card(&post));
-                    }
+            let saved_posts = query_saved_posts(&state.db_pool, &user.id).await?;
 
-                    Ok(HttpResponse::Ok().body(content_cards_html.concat()))
-                }
-                Err(e) => {
-                    println!("{:?}", e);
-                    Ok(HttpResponse::InternalServerError().body(""))
-                }
-            }
+            negotiate(&req, &saved_posts, |saved_posts| {
+                templates::PostCards { posts: saved_posts }.render()
+            })
         },
-        None => Ok(HttpResponse::Unauthorized().body(""))
+        None => Err(EchoError::Unauthorized)
+    }
+}
+
+/// Absolute url of a path on the site, rather than on whatever host the client asked for
+fn absolute_url(state: &AppState, path: &str) -> String {
+    format!("{}{}", state.site_url, path)
+}
+
+/// Absolute url of a user's saved posts feed
+fn saved_feed_url(state: &AppState, feed_token: &str) -> String {
+    absolute_url(state, &format!("/posts/saved/{}/feed.atom", feed_token))
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct FeedTokenResponse {
+    feed_url: String,
+}
+
+// User Create Saved Posts Feed
+#[utoipa::path(
+    post,
+    path = "/auth-actions/feed-token",
+    tag = "auth-actions",
+    responses(
+        (status = 200, description = "Secret url of the user's saved posts feed", body = FeedTokenResponse),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/feed-token")]
+/// Endpoint for creating (or rotating) the secret url of a user's
+/// saved posts feed, any previously issued url stops working.
+pub async fn create_feed_token(
+    state: Data<AppState>,
+    req: HttpRequest,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    match req_user {
+        Some(user) => {
+            // The token is the only thing guarding the feed, so
+            // it needs to be random rather than derived from the user
+            let feed_token = Uuid::new_v4().simple().to_string();
+
+            sqlx::query("UPDATE users SET feed_token = $1 WHERE id = $2")
+                .bind(&feed_token)
+                .bind(user.id)
+                .execute(&state.db_pool)
+                .await?;
+
+            let response = FeedTokenResponse {
+                feed_url: saved_feed_url(&state, &feed_token),
+            };
+            negotiate(&req, &response, |response| {
+                templates::FeedToken { feed_url: &response.feed_url }.render()
+            })
+        }
+        None => Err(EchoError::Unauthorized)
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RevokeFeedTokenResponse {
+    /// Whether there was a feed url to revoke
+    revoked: bool,
+}
+
+// User Revoke Saved Posts Feed
+#[utoipa::path(
+    delete,
+    path = "/auth-actions/feed-token",
+    tag = "auth-actions",
+    responses(
+        (status = 200, description = "The feed url was revoked, if there was one", body = RevokeFeedTokenResponse),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[delete("/feed-token")]
+/// Endpoint for revoking the secret url of a user's saved posts feed
+pub async fn revoke_feed_token(
+    state: Data<AppState>,
+    req: HttpRequest,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    match req_user {
+        Some(user) => {
+            let revoked = sqlx::query("UPDATE users SET feed_token = NULL WHERE id = $1 AND feed_token IS NOT NULL")
+                .bind(user.id)
+                .execute(&state.db_pool)
+                .await?
+                .rows_affected();
+
+            let response = RevokeFeedTokenResponse { revoked: revoked > 0 };
+            negotiate(&req, &response, |_| templates::FeedTokenRevoked.render())
+        }
+        None => Err(EchoError::Unauthorized)
+    }
+}
+
+#[derive(FromRow)]
+struct FeedOwner {
+    id: Uuid,
+    username: String,
+}
+
+// Saved Posts Atom Feed
+#[utoipa::path(
+    get,
+    path = "/posts/saved/{feed_token}/feed.atom",
+    tag = "posts",
+    params(("feed_token" = String, Path, description = "Secret token from `/auth-actions/feed-token`")),
+    responses(
+        (status = 200, description = "The user's saved posts", body = String, content_type = "application/atom+xml"),
+        (status = 404, description = "Unknown or revoked feed token"),
+    ),
+)]
+#[get("/saved/{feed_token}/feed.atom")]
+/// Endpoint serving a user's saved posts as an Atom feed, it's
+/// public but only reachable through the user's secret feed token.
+pub async fn get_saved_feed_atom(
+    state: Data<AppState>,
+    path: Path<String>,
+) -> Result<HttpResponse, EchoError> {
+    // Consume path (feed token) ownership
+    let feed_token = path.into_inner();
+
+    let owner = sqlx::query_as::<_, FeedOwner>("SELECT id, username FROM users WHERE feed_token = $1")
+        .bind(&feed_token)
+        .fetch_optional(&state.db_pool)
+        .await?;
+
+    match owner {
+        Some(owner) => {
+            let saved_posts = query_saved_posts(&state.db_pool, &owner.id).await?;
+            let atom = feed::atom_feed(
+                &format!("{}'s saved posts on Echo", owner.username),
+                &saved_feed_url(&state, &feed_token),
+                &saved_posts,
+            );
+
+            Ok(HttpResponse::Ok().content_type("application/atom+xml; charset=utf-8").body(atom))
+        }
+        None => Err(EchoError::NotFound("feed"))
     }
 }
 
 
-async fn get_post(id: i64, client: Arc<Client>) -> Post {
-    client.get(format!("https://hacker-news.firebaseio.com/v0/item/{}.json", id))
+async fn get_post(id: i64, client: Arc<Client>) -> Result<Option<Post>, reqwest::Error> {
+    Ok(fetch_hn_post(id, &client).await?.map(HnPost::into_post))
+}
+
+/// Fetches a story from hacker news, `None` if there's no story
+/// with that id (it doesn't exist, or it's a comment, job, etc).
+async fn fetch_hn_post(id: i64, client: &Client) -> Result<Option<HnPost>, reqwest::Error> {
+    let item = client.get(format!("https://hacker-news.firebaseio.com/v0/item/{}.json", id))
         .send()
-        .await.unwrap()
-        .json::<HnPost>()
-        .await.unwrap()
-        .into_post()
+        .await?
+        .error_for_status()?
+        .json::<Option<serde_json::Value>>()
+        .await?;
+
+    // Items that aren't stories are missing fields a story has
+    Ok(item.and_then(|item| serde_json::from_value::<HnPost>(item).ok()))
 }
 
 #[derive(Serialize, Deserialize, Hash, Debug, Clone)]
@@ -157,6 +401,8 @@ struct HnPost {
     by: String,
     descendants: i32,
     id: i64,
+    // Missing on stories without comments
+    #[serde(default)]
     kids: Vec<i64>,
     score: i32,
     time: i64,
@@ -176,69 +422,17 @@ struct HnPost {
     }
 }
 
-fn create_post_html_card(post: &Post) -> String {
-    format!("<li 
-            hx-boost='true'
-            key={} 
-            class=\"overflow-hidden bg-secondary rounded-xl border border-gray-200 max-h-44\"
-        >
-        <a href='{}'>
-        <div
-            class=\"w-full group relative cursor-pointer overflow-hidden bg-secondary px-6 pt-1 shadow-xl ring-1 ring-gray-900/5 transition-all duration-300 hover:-translate-y-1 hover:shadow-2xl sm:mx-auto sm:rounded-lg sm:px-10\"
-        >
-            <span class=\"absolute inset-x-0 top-0 h-6 w-full bg-accent transition-all duration-300 group-hover:scale-[100]\"></span>
-            <div class=\"relative z-10 mx-auto max-w-md\">
-                <div
-                    class=\"space-y-1 pt-5 text-base leading-7 text-gray-600 transition-all duration-300 group-hover:text-white/90\"
-                >
-                    <h3 class='truncate text-xl font-extrabold text-white'>{}</h3>
-                    <p class=\"mt-1 truncate text-sm text-gray-100\">Author: {}</p>
-                </div>
-                <form
-                    class='save-post-form flex w-full'
-                    hx-post='/auth-actions/save'
-                    hx-trigger='submit'
-                    hx-target='#save-btn-{}'
-                    hx-swap='outerHTML'
-                    hx-ext='json-enc'
-                    hx-indicator='#spinner'
-                >
-                    <input id='id' name='id' class='invisible hidden' value='{}'></input>
-                    <input id='title' name='title' class='invisible hidden' value='{}'></input>
-                    <input id='author' name='author' class='invisible hidden' value='{}'></input>
-                    <input id='url' name='url' class='invisible hidden' value='{}'></input>
-                    <input id='timestamp' name='timestamp' class='invisible hidden' value='{}'></input>
-                    <button
-                      id='save-btn-{}'
-                      type='submit'
-                      class='save-post-btn rounded-md mx-auto my-5 bg-primary px-3.5 py-2.5 text-sm font-semibold text-white shadow-sm hover:bg-white/50 transition-all duration-300 group-hover:bg-secondary'
-                    >
-                        <span id='save-post-text'>Save Post</span>
-                        <div id='spinner' style='display: none;'>
-                            <svg aria-hidden='true' role='status' class='inline w-4 h-4 mr-3 text-accent text-center animate-spin' viewBox='0 0 100 101' fill='none' xmlns='http://www.w3.org/2000/svg'>
-                                <path d='M100 50.5908C100 78.2051 77.6142 100.591 50 100.591C22.3858 100.591 0 78.2051 0 50.5908C0 22.9766 22.3858 0.59082 50 0.59082C77.6142 0.59082 100 22.9766 100 50.5908ZM9.08144 50.5908C9.08144 73.1895 27.4013 91.5094 50 91.5094C72.5987 91.5094 90.9186 73.1895 90.9186 50.5908C90.9186 27.9921 72.5987 9.67226 50 9.67226C27.4013 9.67226 9.08144 27.9921 9.08144 50.5908Z' fill='#E5E7EB'/>
-                                <path d='M93.9676 39.0409C96.393 38.4038 97.8624 35.9116 97.0079 33.5539C95.2932 28.8227 92.871 24.3692 89.8167 20.348C85.8452 15.1192 80.8826 10.7238 75.2124 7.41289C69.5422 4.10194 63.2754 1.94025 56.7698 1.05124C51.7666 0.367541 46.6976 0.446843 41.7345 1.27873C39.2613 1.69328 37.813 4.19778 38.4501 6.62326C39.0873 9.04874 41.5694 10.4717 44.0505 10.1071C47.8511 9.54855 51.7191 9.52689 55.5402 10.0491C60.8642 10.7766 65.9928 12.5457 70.6331 15.2552C75.2735 17.9648 79.3347 21.5619 82.5849 25.841C84.9175 28.9121 86.7997 32.2913 88.1811 35.8758C89.083 38.2158 91.5421 39.6781 93.9676 39.0409Z' fill='currentColor'/>
-                            </svg>
-                        </div>
-                    </button>
-                </form>
-            </div>
-        </div>
-        </a>
-      </li>", &post.id, &post.url, &post.title, &post.author, &post.id, &post.id, post.title, post.author, post.url, post.timestamp, post.id)
-}
-
-
-#[get("/feed")]
-pub async fn get_feed(
-    state: Data<AppState>,
-) -> Result<HttpResponse, EchoError> {
+/// Fetches the current best stories on hacker news, leaving
+/// out the "Show HN: " & "Ask HN: " style posts.
+async fn fetch_feed() -> Result<Vec<Post>, EchoError> {
     let client = Arc::new(Client::new());
 
-    let post_ids = reqwest::get("https://hacker-news.firebaseio.com/v0/beststories.json")
-        .await.unwrap()
+    let post_ids = client.get("https://hacker-news.firebaseio.com/v0/beststories.json")
+        .send()
+        .await?
+        .error_for_status()?
         .json::<Vec<i64>>()
-        .await.unwrap();
+        .await?;
 
     // Use a VecDeque to handle asynchronous requests
     let mut request_queue: VecDeque<_> = post_ids
@@ -255,20 +449,136 @@ pub async fn get_feed(
         join_handles.push(handle);
     }
 
-    // Wait for all requests to complete
+    // Wait for all requests to complete, one bad item
+    // shouldn't take the whole feed down with it
     let mut feed: Vec<Post> = Vec::new();
-    let mut content_cards_html: Vec<String> = Vec::new();
     let _ = tokio::join!(async {
         for handle in join_handles {
-            let post = handle.await.expect("Failed to join a task");
-            if !post.title.contains("HN: ") {
-                content_cards_html.push(create_post_html_card(&post));
-                feed.push(post);
+            match handle.await {
+                Ok(Ok(Some(post))) => {
+                    if !post.title.contains("HN: ") {
+                        feed.push(post);
+                    }
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => log::warn!("skipping hn item: error={}", e),
+                Err(e) => log::error!("hn item task failed: error={}", e),
             }
         }
     });
 
-    // Concatenate the HTML cards into one string
-    Ok(HttpResponse::Ok().body(content_cards_html.concat()))
+    Ok(feed)
+}
+
+#[utoipa::path(
+    get,
+    path = "/posts/feed",
+    tag = "posts",
+    responses((status = 200, description = "The current feed", body = [Post])),
+)]
+#[get("/feed")]
+pub async fn get_feed(
+    _state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, EchoError> {
+    let feed = fetch_feed().await?;
+
+    negotiate(&req, &feed, |feed| templates::PostCards { posts: feed }.render())
+}
+
+const FEED_TITLE: &str = "Echo";
+
+#[utoipa::path(
+    get,
+    path = "/posts/feed.atom",
+    tag = "posts",
+    responses((status = 200, description = "The current feed", body = String, content_type = "application/atom+xml")),
+)]
+#[get("/feed.atom")]
+/// Endpoint serving the feed as an Atom feed
+pub async fn get_feed_atom(
+    state: Data<AppState>,
+) -> Result<HttpResponse, EchoError> {
+    let feed = fetch_feed().await?;
+    let atom = feed::atom_feed(FEED_TITLE, &absolute_url(&state, "/posts/feed.atom"), &feed);
+
+    Ok(HttpResponse::Ok().content_type("application/atom+xml; charset=utf-8").body(atom))
+}
+
+#[utoipa::path(
+    get,
+    path = "/posts/feed.rss",
+    tag = "posts",
+    responses((status = 200, description = "The current feed", body = String, content_type = "application/rss+xml")),
+)]
+#[get("/feed.rss")]
+/// Endpoint serving the feed as an RSS feed
+pub async fn get_feed_rss(
+    state: Data<AppState>,
+) -> Result<HttpResponse, EchoError> {
+    let feed = fetch_feed().await?;
+    let rss = feed::rss_feed(FEED_TITLE, &absolute_url(&state, "/"), &feed);
+
+    Ok(HttpResponse::Ok().content_type("application/rss+xml; charset=utf-8").body(rss))
+}
+
+#[utoipa::path(
+    get,
+    path = "/posts/feed.json",
+    tag = "posts",
+    responses((status = 200, description = "The current feed as a JSON Feed 1.1 document", body = Object, content_type = "application/feed+json")),
+)]
+#[get("/feed.json")]
+/// Endpoint serving the feed as a JSON Feed
+pub async fn get_feed_json(
+    state: Data<AppState>,
+) -> Result<HttpResponse, EchoError> {
+    let feed = fetch_feed().await?;
+    let json_feed = feed::json_feed(
+        FEED_TITLE,
+        &absolute_url(&state, "/"),
+        &absolute_url(&state, "/posts/feed.json"),
+        &feed,
+    );
+
+    Ok(HttpResponse::Ok().content_type("application/feed+json").json(json_feed))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save(source: &str, id: &str) -> Result<SavePost, Vec<&'static str>> {
+        let request = SaveRequest { source: source.to_string(), id: id.to_string() };
+        SavePost::try_from(request).map_err(|invalid| invalid.errors.iter().map(|error| error.field).collect())
+    }
+
+    #[test]
+    fn saves_hacker_news_posts_by_id() {
+        let post = save("hn", "8863").unwrap();
+        assert!(matches!(post.source, Source::HackerNews));
+        assert_eq!(post.id, 8863);
+
+        assert_eq!(save("hn", &i64::MAX.to_string()).unwrap().id, i64::MAX);
+    }
+
+    #[test]
+    fn rejects_unknown_sources() {
+        for source in ["", "HN", "Hn", "reddit", "hn ", "ｈｎ"] {
+            assert_eq!(save(source, "8863").unwrap_err(), ["source"], "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn rejects_ids_that_arent_positive_integers() {
+        let overflow = (i64::MAX as u64 + 1).to_string();
+        for id in ["", "0", "-1", "1.5", " 8863", "8863\n", "0x22af", "٨٨٦٣", overflow.as_str()] {
+            assert_eq!(save("hn", id).unwrap_err(), ["id"], "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn reports_every_invalid_field() {
+        assert_eq!(save("", "").unwrap_err(), ["source", "id"]);
+    }
+}
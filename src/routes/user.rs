@@ -1,163 +1,967 @@
-use crate::{error::EchoError, structs::{AppState, TokenClaims}};
+use crate::{auth, error::EchoError, routes::negotiate::{negotiate, wants_json}, structs::{AppState, FieldError, Role, TokenClaims, ValidationErrors}, templates};
+use chrono::{DateTime, Utc};
+use anyhow::Context;
+use askama::Template;
 use actix_web::{
+    cookie::{time::Duration as CookieDuration, Cookie, SameSite},
+    delete,
+    get,
     post,
-    web::Data,
+    web::{Data, Form, Query, ReqData},
+    HttpRequest,
     HttpResponse,
     HttpMessage,
     dev::ServiceRequest
 };
 use std::result::Result;
 use uuid::Uuid;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 use serde::{Serialize, Deserialize};
-use sqlx::FromRow;
-use sha2::Sha256;
+use utoipa::{IntoParams, ToSchema};
+use sqlx::{FromRow, PgExecutor, PgPool};
 use actix_web_httpauth::extractors::{
     bearer::{self, BearerAuth},
     AuthenticationError,
 };
-use hmac::{Hmac, Mac};
-use jwt::VerifyWithKey;
-use jwt::SignWithKey;
 
 
-pub async fn token_validator(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
-    // grab secret and create Hmac key with it 
-    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET env var must be set!");
-    let key: Hmac<Sha256> = Hmac::new_from_slice(jwt_secret.as_bytes()).unwrap();
+/// Session cookie the website signs in with, holds a refresh token
+pub const SESSION_COOKIE: &str = "echo_session";
+/// Cookie holding the session's CSRF token, readable by the website's scripts
+pub const CSRF_COOKIE: &str = "echo_csrf";
+/// Header cookie authed requests echo the CSRF token back in
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
 
-    // Verify the token
-    let token_string = credentials.token();
-    let claims: Result<TokenClaims, &str> = token_string
-        .verify_with_key(&key)
-        .map_err(|_| "Invalid token!");
+/// Authenticates the `auth-actions` routes with either a bearer token
+/// or the website's session cookie
+pub async fn token_validator(req: ServiceRequest, credentials: Option<BearerAuth>) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    match credentials {
+        Some(credentials) => validate_bearer(req, credentials).await,
+        None => validate_session(req).await,
+    }
+}
+
+async fn validate_bearer(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let state = match app_state(&req) {
+        Ok(state) => state,
+        Err(e) => return Err((e.into(), req)),
+    };
 
-    match claims {
-        Ok(val) => {
-            req.extensions_mut().insert(val);
+    // Verify the token, tokens from before claims had an expiry fail to decode
+    let mut claims = match state.jwt_keys.verify::<TokenClaims>(credentials.token()) {
+        Some(claims) => claims,
+        None => return Err((invalid_token(&req), req)),
+    };
+
+    // Logged out tokens & tokens of disabled users stay valid until
+    // they expire, unless denied here
+    match token_user_role(&state.db_pool, &claims).await {
+        Ok(Some(role)) => {
+            // Role changes apply right away rather than once the token's refreshed
+            claims.role = role;
+            req.extensions_mut().insert(claims);
             Ok(req)
         }
-        Err(_) => {
-            let config = req.app_data::<bearer::Config>().cloned().unwrap_or_default().scope("");
-            Err((AuthenticationError::from(config).into(), req))
+        Ok(None) => Err((invalid_token(&req), req)),
+        Err(e) => Err((EchoError::from(e).into(), req)),
+    }
+}
+
+/// Cookies get sent along no matter which site made the request, so
+/// anything but a read has to echo the CSRF cookie back in a header,
+/// which only our own pages can read
+async fn validate_session(req: ServiceRequest) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let session = match req.cookie(SESSION_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Err((invalid_token(&req), req)),
+    };
+
+    if !req.method().is_safe() {
+        let csrf_cookie = req.cookie(CSRF_COOKIE);
+        let csrf_header = req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok());
+        let csrf_matches = match (&csrf_cookie, csrf_header) {
+            (Some(cookie), Some(header)) => !header.is_empty() && cookie.value() == header,
+            _ => false,
+        };
+        if !csrf_matches {
+            return Err((EchoError::Forbidden("missing or invalid CSRF token").into(), req));
+        }
+    }
+
+    let state = match app_state(&req) {
+        Ok(state) => state,
+        Err(e) => return Err((e.into(), req)),
+    };
+    match session_user(&state.db_pool, &session).await {
+        Ok(Some((user_id, role))) => {
+            // The session stands in for a token for the length of this request
+            req.extensions_mut().insert(TokenClaims::new(user_id, role, auth::ACCESS_TOKEN_TTL));
+            Ok(req)
         }
+        Ok(None) => Err((invalid_token(&req), req)),
+        Err(e) => Err((EchoError::from(e).into(), req)),
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct SignUpPayload {
-    hash: String
+/// The user a website session belongs to & their role, if it's still valid
+pub async fn session_user(pool: &PgPool, session: &str) -> Result<Option<(Uuid, Role)>, sqlx::Error> {
+    sqlx::query_as::<_, (Uuid, Role)>(
+        "SELECT users.id, users.role FROM refresh_tokens 
+        JOIN users ON users.id = refresh_tokens.user_id
+        WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > now() AND users.disabled_at IS NULL",
+    )
+    .bind(auth::hash_refresh_token(session))
+    .fetch_optional(pool)
+    .await
+}
+
+fn app_state(req: &ServiceRequest) -> Result<Data<AppState>, EchoError> {
+    let state = req.app_data::<Data<AppState>>().context("app state is missing")?;
+    Ok(state.clone())
+}
+
+/// Cookies for a website session, the session itself & its CSRF token
+pub fn session_cookies(refresh_token: String) -> (Cookie<'static>, Cookie<'static>) {
+    let max_age = CookieDuration::seconds(auth::REFRESH_TOKEN_TTL.as_secs() as i64);
+    let session = Cookie::build(SESSION_COOKIE, refresh_token)
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .max_age(max_age)
+        .finish();
+    let csrf = Cookie::build(CSRF_COOKIE, auth::generate_csrf_token())
+        .path("/")
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .max_age(max_age)
+        .finish();
+
+    (session, csrf)
+}
+
+fn invalid_token(req: &ServiceRequest) -> actix_web::Error {
+    let config = req.app_data::<bearer::Config>().cloned().unwrap_or_default().scope("");
+    EchoError::from(AuthenticationError::from(config)).into()
+}
+
+/// The token's user's current role, unless the token's been revoked
+/// or the user's been disabled (or deleted)
+async fn token_user_role(pool: &PgPool, claims: &TokenClaims) -> Result<Option<Role>, sqlx::Error> {
+    sqlx::query_scalar::<_, Role>(
+        "SELECT role FROM users
+        WHERE id = $1 AND disabled_at IS NULL
+            AND NOT EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $2)",
+    )
+    .bind(claims.id)
+    .bind(claims.jti)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Signs a new access token for the user & stores a new refresh token
+/// for getting the next one, returns both
+pub async fn issue_tokens(state: &AppState, user_id: Uuid) -> Result<(String, String), EchoError> {
+    let account = sqlx::query_as::<_, (Role, Option<DateTime<Utc>>)>("SELECT role, disabled_at FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.db_pool)
+        .await?
+        .ok_or(EchoError::Unauthorized)?;
+    let role = match account {
+        (role, None) => role,
+        (_, Some(_)) => return Err(EchoError::Forbidden("this account is disabled")),
+    };
+
+    let claims = TokenClaims::new(user_id, role, auth::ACCESS_TOKEN_TTL);
+    let token = state.jwt_keys.sign(&claims).context("failed to sign token")?;
+
+    let refresh_token = auth::generate_refresh_token();
+    sqlx::query(
+        "INSERT INTO refresh_tokens 
+            (token_hash, user_id, expires_at) 
+        VALUES
+            ($1, $2, now() + $3 * interval '1 second')",
+    )
+    .bind(auth::hash_refresh_token(&refresh_token))
+    .bind(user_id)
+    .bind(auth::REFRESH_TOKEN_TTL.as_secs() as i64)
+    .execute(&state.db_pool)
+    .await?;
+
+    Ok((token, refresh_token))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct SignUpPayload {
+    id: Uuid,
+    username: String,
+    /// Key to sign in with, only for anonymous accounts & only shown this once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>
+}
+
+/// Sign up form, leaving out the password signs up an anonymous
+/// account that signs in with a hash key instead (if enabled)
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct SignUpForm {
+    /// 3 to 32 letters, digits, `_`, `-` or `.`, starting with a letter or digit
+    #[serde(default)]
+    username: String,
+    password: Option<String>,
+}
+
+/// A sign up form that's been checked field by field
+struct SignUp {
+    username: String,
+    password: Option<String>,
+}
+impl TryFrom<SignUpForm> for SignUp {
+    type Error = ValidationErrors;
+
+    fn try_from(form: SignUpForm) -> Result<Self, Self::Error> {
+        let mut errors = Vec::new();
+
+        let username = match validate_username(&form.username) {
+            Ok(username) => Some(username.to_string()),
+            Err(error) => {
+                errors.push(error);
+                None
+            }
+        };
+
+        let password = non_empty(form.password);
+        if let Some(password) = &password {
+            let password_len = password.chars().count();
+            if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password_len) {
+                let message = format!("must be {} to {} characters", MIN_PASSWORD_LEN, MAX_PASSWORD_LEN);
+                errors.push(FieldError::new("password", message));
+            }
+        }
+
+        match username {
+            Some(username) if errors.is_empty() => Ok(SignUp { username, password }),
+            _ => Err(ValidationErrors { errors }),
+        }
+    }
 }
+
+/// Form fields are sent even when left empty
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.is_empty())
+}
+
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_PASSWORD_LEN: usize = 128;
+const MIN_USERNAME_LEN: usize = 3;
+pub const MAX_USERNAME_LEN: usize = 32;
+/// Usernames that could pass for the site itself or the people running it
+const RESERVED_USERNAMES: [&str; 16] = [
+    "admin", "administrator", "anonymous", "api", "echo", "mod", "moderator", "null",
+    "root", "saved", "staff", "support", "system", "undefined", "user", "users",
+];
+
+/// Checks a username someone picked, returning it trimmed
+pub fn validate_username(username: &str) -> Result<&str, FieldError> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err(FieldError::new("username", "is required"));
+    }
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&username.chars().count()) {
+        let message = format!("must be {} to {} characters", MIN_USERNAME_LEN, MAX_USERNAME_LEN);
+        return Err(FieldError::new("username", message));
+    }
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    if !username.chars().all(allowed) || !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        let message = "must be letters, digits, `_`, `-` or `.`, starting with a letter or digit";
+        return Err(FieldError::new("username", message));
+    }
+    if RESERVED_USERNAMES.contains(&username.to_ascii_lowercase().as_str()) {
+        return Err(FieldError::new("username", "is reserved"));
+    }
+
+    Ok(username)
+}
+
+/// Whether someone other than `except` has the username, ignoring case
+pub async fn is_username_taken<'e>(
+    executor: impl PgExecutor<'e>,
+    username: &str,
+    except: Option<Uuid>,
+) -> Result<bool, sqlx::Error> {
+    let taken = sqlx::query("SELECT 1 FROM users WHERE lower(username) = lower($1) AND id IS DISTINCT FROM $2")
+        .bind(username)
+        .bind(except)
+        .fetch_optional(executor)
+        .await?
+        .is_some();
+    Ok(taken)
+}
+
+fn username_taken_error() -> EchoError {
+    ValidationErrors { errors: vec![FieldError::new("username", "is already taken")] }.into()
+}
+
+/// Someone can still take the username between checking & using it,
+/// which the unique index catches
+pub fn username_conflict(err: sqlx::Error) -> EchoError {
+    let constraint = err.as_database_error().and_then(|err| err.constraint());
+    match constraint {
+        Some("users_username_lower_key") => username_taken_error(),
+        _ => err.into(),
+    }
+}
+
+/// Result of checking a username on the sign up page
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UsernameAvailability {
+    username: String,
+    available: bool,
+    /// Why it's not available
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct UsernameQuery {
+    #[serde(default)]
+    username: String,
+}
+
+// Username Availability
+#[utoipa::path(
+    get,
+    path = "/user/username-available",
+    tag = "user",
+    params(UsernameQuery),
+    responses(
+        (status = 200, description = "Whether the username can be signed up with", body = UsernameAvailability),
+    ),
+)]
+#[get("username-available")]
+/// Endpoint checking a username as it's typed into the sign up page
+pub async fn username_available(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: Query<UsernameQuery>,
+) -> Result<HttpResponse, EchoError> {
+    let message = match validate_username(&query.username) {
+        Ok(username) if is_username_taken(&state.db_pool, username, None).await? => Some(String::from("is already taken")),
+        Ok(_) => None,
+        Err(error) => Some(error.message),
+    };
+    let response = UsernameAvailability {
+        username: query.username.trim().to_string(),
+        available: message.is_none(),
+        message,
+    };
+
+    negotiate(&req, &response, |response| {
+        templates::UsernameAvailable { available: response.available, message: response.message.as_deref() }.render()
+    })
+}
+
 // User Sign Up
+#[utoipa::path(
+    post,
+    path = "/user/sign-up",
+    tag = "user",
+    request_body(content = SignUpForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The new account, & its hash key for anonymous accounts", body = SignUpPayload),
+        (status = 400, description = "Invalid fields", body = ValidationErrors),
+    ),
+)]
 #[post("sign-up")]
-/// Example endpoint to echo back a payload from a POST request
+/// Endpoint for creating an account
 pub async fn sign_up(
     state: Data<AppState>,
-    payload: String,
+    req: HttpRequest,
+    payload: Form<SignUpForm>,
 ) -> Result<HttpResponse, EchoError> {
-    let username = payload.split("=").collect::<Vec<&str>>()[1];
+    state.auth_limits.check(&req, None)?;
+
+    // Consume payload ownership
+    let SignUp { username, password } = SignUp::try_from(payload.into_inner())?;
+    let username = username.as_str();
+
+    if is_username_taken(&state.db_pool, username, None).await? {
+        return Err(username_taken_error());
+    }
 
     // Create user id
     let user_id = Uuid::new_v4();
 
-    // Generate hash for user to store
-    let mut hasher = DefaultHasher::new();
-    user_id.hash(&mut hasher);
-    let response = SignUpPayload {
-        hash: format!("{:#01x}", hasher.finish())
+    let response = match password {
+        Some(password) => {
+            let password_hash = auth::hash_password(password).await?;
+            sqlx::query(
+                "INSERT INTO users 
+                    (id, username, password_hash) 
+                VALUES
+                    ($1, $2, $3)",
+            )
+            .bind(user_id)
+            .bind(username)
+            .bind(&password_hash)
+            .execute(&state.db_pool)
+            .await
+            .map_err(username_conflict)?;
+
+            SignUpPayload { id: user_id, username: username.to_string(), hash: None }
+        }
+        None if state.anonymous_sign_up => {
+            // Generate a key for the user to store, only its hash is kept
+            let key = auth::generate_key(user_id);
+            let key_hash = auth::hash_password(key.clone()).await?;
+
+            // Create row in db user table
+            sqlx::query(
+                "INSERT INTO users 
+                    (id, username, key_hash) 
+                VALUES
+                    ($1, $2, $3)",
+            )
+            .bind(user_id)
+            .bind(username)
+            .bind(&key_hash)
+            .execute(&state.db_pool)
+            .await
+            .map_err(username_conflict)?;
+
+            SignUpPayload { id: user_id, username: username.to_string(), hash: Some(key) }
+        }
+        None => {
+            return Err(ValidationErrors { errors: vec![FieldError::new("password", "is required")] }.into());
+        }
     };
 
-    // Create row in db user table
-    match sqlx::query(
-        "INSERT INTO users 
-            (id, username, hash) 
-        VALUES
-            ($1, $2, $3)",
-    )
-    .bind(&user_id)
-    .bind(&username)
-    .bind(&response.hash)
-    .execute(&state.db_pool)
-    .await
-    {   
-        Ok(_) => {
-            let sign_up_html = format!("<div class='bg-secondary shadow sm:rounded-lg p-6 mx-auto mt-10'>
-        <h3 class='px-4 text-base font-semibold leading-6 text-white text-center'>Save Your Hash Key</h3>
-        <div class='mt-2 text-sm text-gray-300 text-center'>
-          <p>Copy & Paste your hash key somewhere safe-ish, notes on mobile or text file on desktop is probably fine.</p>
-        </div>
-        <div
-          hx-boost='true'
-          class='flex flex-col items-center w-full'>
-          <div class='flex pt-3 mb-5 w-full mx-auto items-center'>
-            <label class='text-xs font-medium text-white ml-auto align-middle'>
-              Private Key: 
-            </label>
-            <input
-              id='hash-key'
-              class='inline-block w-1/3 rounded-md ml-1 mr-auto border-0 py-1.5 text-gray-900 shadow-sm ring-1 ring-inset ring-gray-300 placeholder:text-gray-400 focus:ring-2 focus:ring-inset focus:ring-indigo-600 sm:text-sm sm:leading-6 text-center'
-              value='{}'
-            ></input>
-          </div>
-          <a
-            href='/sign-in'
-            class='mt-10 w-32 text-center items-center justify-center rounded-md bg-accent px-3 py-2 text-sm font-semibold text-white shadow-sm hover:bg-indigo-500 focus-visible:outline focus-visible:outline-2 focus-visible:outline-offset-2 focus-visible:outline-indigo-600'
-          >
-            I Saved It
-          </a>
-        </div>", response.hash);
-            Ok(HttpResponse::Ok().body(sign_up_html))
-        }
-        Err(e) => {
-            println!("{:?}", e);
-            Ok(HttpResponse::InternalServerError().body(""))
-        }
-    }
-}
-
-
-#[derive(Serialize, Deserialize, Debug, FromRow)]
-struct SignInResponse {
+    negotiate(&req, &response, |response| match &response.hash {
+        Some(hash) => templates::SignUpKey { hash }.render(),
+        None => templates::SignedUp { username: &response.username }.render(),
+    })
+}
+
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct TokenResponse {
+    /// Short lived bearer token for the `auth-actions` routes
+    pub token: String,
+    /// Trades in for a new token at `/user/refresh`, only works once
+    pub refresh_token: String,
+}
+
+/// Sign in form, either a username & password or the hash key
+/// of an anonymous account
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct SignInForm {
+    username: Option<String>,
+    password: Option<String>,
+    key: Option<String>,
+}
+
+#[derive(FromRow)]
+struct KeyAccount {
     id: Uuid,
-    username: String,
+    key_hash: String,
 }
 
-// User Sign Up
+#[derive(FromRow)]
+struct PasswordAccount {
+    id: Uuid,
+    password_hash: String,
+}
+
+/// The user signing in with a username & password or a key
+async fn authenticate(
+    state: &AppState,
+    credentials: (Option<String>, Option<String>, Option<String>),
+) -> Result<Uuid, EchoError> {
+    match credentials {
+        (Some(username), Some(password), _) => {
+            let account = sqlx::query_as::<_, PasswordAccount>(
+                "SELECT id, password_hash FROM users 
+                WHERE lower(username) = lower($1) AND password_hash IS NOT NULL",
+            )
+            .bind(&username)
+            .fetch_optional(&state.db_pool)
+            .await?;
+
+            // Unknown usernames are checked against a dummy hash, so they take as long to turn away
+            let password_hash = account.as_ref().map(|account| account.password_hash.clone());
+            match (account, auth::verify_account_password(password, password_hash).await?) {
+                (Some(account), true) => Ok(account.id),
+                _ => Err(EchoError::Unauthorized),
+            }
+        }
+        (_, _, Some(key)) => match auth::key_owner(&key) {
+            Some(user_id) => {
+                let account = sqlx::query_as::<_, KeyAccount>(
+                    "SELECT id, key_hash FROM users 
+                    WHERE id = $1 AND key_hash IS NOT NULL",
+                )
+                .bind(user_id)
+                .fetch_optional(&state.db_pool)
+                .await?;
+
+                let key_hash = account.as_ref().map(|account| account.key_hash.clone());
+                match (account, auth::verify_account_password(key, key_hash).await?) {
+                    (Some(account), true) => Ok(account.id),
+                    _ => Err(EchoError::Unauthorized),
+                }
+            }
+            None => {
+                // Legacy keys were derived from the user's id, so they're revoked
+                let message = "keys from before keys were random no longer work, ask an admin for a new one";
+                Err(ValidationErrors { errors: vec![FieldError::new("key", message)] }.into())
+            }
+        },
+        _ => {
+            let message = "a key, or a username & password, is required";
+            Err(ValidationErrors { errors: vec![FieldError::new("key", message)] }.into())
+        }
+    }
+}
+
+// User Sign In
+#[utoipa::path(
+    post,
+    path = "/user/sign-in",
+    tag = "user",
+    request_body(content = SignInForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "A bearer token for the `auth-actions` routes", body = TokenResponse),
+        (status = 400, description = "Neither a key nor a username & password, or a revoked legacy key", body = ValidationErrors),
+        (status = 401, description = "Wrong credentials"),
+    ),
+)]
 #[post("sign-in")]
-/// Example endpoint to echo back a payload from a POST request
+/// Endpoint for signing in, responds with a token for the `auth-actions` routes
 pub async fn sign_in(
     state: Data<AppState>,
-    payload: String,
+    req: HttpRequest,
+    payload: Form<SignInForm>,
+) -> Result<HttpResponse, EchoError> {
+    // Consume payload ownership
+    let form = payload.into_inner();
+    let credentials = (non_empty(form.username), non_empty(form.password), non_empty(form.key));
+
+    // Guesses count against the account being signed in to, as well as the client
+    let account = match &credentials {
+        (Some(username), Some(_), _) => Some(format!("username:{}", username.to_lowercase())),
+        (_, _, Some(key)) => auth::key_owner(key).map(|user_id| format!("key:{}", user_id)),
+        _ => None,
+    };
+    state.auth_limits.check(&req, account.as_deref())?;
+    let signed_in = authenticate(&state, credentials).await;
+    state.auth_limits.record(account.as_deref(), &signed_in);
+    let user_id = signed_in?;
+
+    let (token_str, refresh_token) = issue_tokens(&state, user_id).await?;
+
+    // Scripts get the tokens as JSON
+    if wants_json(&req) {
+        return Ok(HttpResponse::Ok().json(TokenResponse { token: token_str, refresh_token }));
+    }
+
+    // The website signs in with a session cookie instead of the tokens
+    let (session, csrf) = session_cookies(refresh_token);
+    Ok(HttpResponse::Ok()
+        .insert_header(("HX-Location", format!("{}/", state.site_url)))
+        .cookie(session)
+        .cookie(csrf)
+        .finish())
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct RefreshForm {
+    refresh_token: String,
+}
+
+// Refresh Token
+#[utoipa::path(
+    post,
+    path = "/user/refresh",
+    tag = "user",
+    request_body(content = RefreshForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "A new token & refresh token, the used refresh token stops working", body = TokenResponse),
+        (status = 401, description = "Unknown, expired or already used refresh token"),
+    ),
+)]
+#[post("refresh")]
+/// Endpoint trading a refresh token for a new token & refresh token
+pub async fn refresh(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: Form<RefreshForm>,
 ) -> Result<HttpResponse, EchoError> {
-    // Consume path (hash) ownership
-    let hash = payload.split("=").collect::<Vec<&str>>()[1];
-
-    let jwt_secret: Hmac<Sha256> = Hmac::new_from_slice(
-        std::env::var("JWT_SECRET")
-        .expect("JWT_SECRET env var must be set!")
-        .as_bytes()
-    ).unwrap();
-
-    // Query db for user with hash
-    match sqlx::query_as::<_, SignInResponse>(
-        "SELECT id, username FROM users 
-        WHERE hash = $1",
+    state.auth_limits.check(&req, None)?;
+
+    // Refresh tokens are rotated, so revoke this one as it's used
+    let user_id = sqlx::query_scalar::<_, Uuid>(
+        "UPDATE refresh_tokens SET revoked_at = now() 
+        WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > now() 
+        RETURNING user_id",
     )
-    .bind(&hash)
-    .fetch_one(&state.db_pool)
+    .bind(auth::hash_refresh_token(&payload.refresh_token))
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or(EchoError::Unauthorized)?;
+
+    let (token, refresh_token) = issue_tokens(&state, user_id).await?;
+    Ok(HttpResponse::Ok().json(TokenResponse { token, refresh_token }))
+}
+
+/// The refresh token to revoke, the website's session cookie is used if left out
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct LogoutForm {
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+// Logout
+#[utoipa::path(
+    post,
+    path = "/auth-actions/logout",
+    tag = "auth-actions",
+    request_body(content = LogoutForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 204, description = "The token & refresh token (or session) are revoked"),
+        (status = 401, description = "Missing or invalid bearer token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("logout")]
+/// Endpoint revoking the signed in token & its refresh token, or the session
+pub async fn logout(
+    state: Data<AppState>,
+    req: HttpRequest,
+    req_user: Option<ReqData<TokenClaims>>,
+    payload: Form<LogoutForm>,
+) -> Result<HttpResponse, EchoError> {
+    let claims = req_user.ok_or(EchoError::Unauthorized)?.into_inner();
+    let session = req.cookie(SESSION_COOKIE);
+    let refresh_token = match (payload.into_inner().refresh_token, &session) {
+        (Some(refresh_token), _) => refresh_token,
+        (None, Some(session)) => session.value().to_string(),
+        (None, None) => {
+            return Err(ValidationErrors { errors: vec![FieldError::new("refresh_token", "is required")] }.into());
+        }
+    };
+
+    sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = now() 
+        WHERE token_hash = $1 AND user_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(auth::hash_refresh_token(&refresh_token))
+    .bind(claims.id)
+    .execute(&state.db_pool)
+    .await?;
+
+    // The token stays denied until it would've expired anyway,
+    // so clear out the ones that have while we're here
+    sqlx::query("DELETE FROM revoked_tokens WHERE expires_at <= now()")
+        .execute(&state.db_pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO revoked_tokens (jti, expires_at) 
+        VALUES ($1, to_timestamp($2)) 
+        ON CONFLICT DO NOTHING",
+    )
+    .bind(claims.jti)
+    .bind(claims.exp)
+    .execute(&state.db_pool)
+    .await?;
+
+    let mut response = HttpResponse::NoContent();
+    if session.is_some() {
+        let (mut session, mut csrf) = session_cookies(String::new());
+        session.make_removal();
+        csrf.make_removal();
+        response.cookie(session).cookie(csrf);
+    }
+    Ok(response.finish())
+}
+
+/// The signed in user's account, & how they can sign in to it
+#[derive(Serialize, Debug, FromRow, ToSchema)]
+pub struct Account {
+    pub id: Uuid,
+    pub username: String,
+    pub has_password: bool,
+    /// Whether the account has a hash key to sign in with
+    pub has_key: bool,
+    pub passkeys: i64,
+    pub saved_posts: i64,
+    /// Whether the saved posts feed url is turned on
+    pub has_feed: bool,
+}
+
+async fn query_account(pool: &PgPool, user_id: Uuid) -> Result<Option<Account>, sqlx::Error> {
+    sqlx::query_as::<_, Account>(
+        "SELECT
+            id,
+            username,
+            password_hash IS NOT NULL AS has_password,
+            (key_hash IS NOT NULL OR hash IS NOT NULL) AS has_key,
+            (SELECT count(*) FROM passkeys WHERE user_id = users.id) AS passkeys,
+            (SELECT count(DISTINCT post_id) FROM unnest(saved_posts) AS post_id) AS saved_posts,
+            feed_token IS NOT NULL AS has_feed
+        FROM users
+        WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
     .await
-    {   
-        Ok(row) => {
-            let claims = TokenClaims { id: row.id };
-            let token_str = claims.sign_with_key(&jwt_secret).unwrap();
+}
+
+// User Account
+#[utoipa::path(
+    get,
+    path = "/auth-actions/account",
+    tag = "auth-actions",
+    responses(
+        (status = 200, description = "The signed in user's account", body = Account),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("account")]
+/// Endpoint for viewing the signed in user's account
+pub async fn get_account(
+    state: Data<AppState>,
+    req: HttpRequest,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let user = req_user.ok_or(EchoError::Unauthorized)?;
+    let account = query_account(&state.db_pool, user.id).await?.ok_or(EchoError::Unauthorized)?;
+
+    negotiate(&req, &account, |account| templates::AccountSettings { account }.render())
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ChangeUsernameForm {
+    /// Same rules as signing up
+    #[serde(default)]
+    username: String,
+}
+
+// Change Username
+#[utoipa::path(
+    post,
+    path = "/auth-actions/account/username",
+    tag = "auth-actions",
+    request_body(content = ChangeUsernameForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The account with its new username", body = Account),
+        (status = 400, description = "Invalid or taken username", body = ValidationErrors),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("account/username")]
+/// Endpoint for changing the signed in user's username
+pub async fn change_username(
+    state: Data<AppState>,
+    req: HttpRequest,
+    req_user: Option<ReqData<TokenClaims>>,
+    payload: Form<ChangeUsernameForm>,
+) -> Result<HttpResponse, EchoError> {
+    let user = req_user.ok_or(EchoError::Unauthorized)?;
+    let username = validate_username(&payload.username).map_err(|error| ValidationErrors { errors: vec![error] })?;
+
+    if is_username_taken(&state.db_pool, username, Some(user.id)).await? {
+        return Err(username_taken_error());
+    }
+
+    sqlx::query("UPDATE users SET username = $2 WHERE id = $1")
+        .bind(user.id)
+        .bind(username)
+        .execute(&state.db_pool)
+        .await
+        .map_err(username_conflict)?;
+
+    let account = query_account(&state.db_pool, user.id).await?.ok_or(EchoError::Unauthorized)?;
+    negotiate(&req, &account, |account| templates::AccountSettings { account }.render())
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct KeyResponse {
+    /// Key to sign in with, only shown this once
+    pub key: String,
+}
+
+// Regenerate Key
+#[utoipa::path(
+    post,
+    path = "/auth-actions/account/key",
+    tag = "auth-actions",
+    responses(
+        (status = 200, description = "A new hash key, the old one stops working", body = KeyResponse),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("account/key")]
+/// Endpoint for replacing the signed in user's hash key, password
+/// accounts get a key to sign in with alongside their password
+pub async fn regenerate_key(
+    state: Data<AppState>,
+    req: HttpRequest,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let user = req_user.ok_or(EchoError::Unauthorized)?;
+
+    let key = auth::generate_key(user.id);
+    let key_hash = auth::hash_password(key.clone()).await?;
+    let updated = sqlx::query("UPDATE users SET key_hash = $2 WHERE id = $1")
+        .bind(user.id)
+        .bind(&key_hash)
+        .execute(&state.db_pool)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Err(EchoError::Unauthorized);
+    }
+    log::info!("regenerated key: user_id={}", user.id);
+
+    let response = KeyResponse { key };
+    negotiate(&req, &response, |response| templates::NewKey { key: &response.key }.render())
+}
+
+// Delete Account
+#[utoipa::path(
+    delete,
+    path = "/auth-actions/account",
+    tag = "auth-actions",
+    responses(
+        (status = 204, description = "The account, its saved posts & its sessions are gone"),
+        (status = 401, description = "Missing or invalid token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[delete("account")]
+/// Endpoint deleting the signed in user's account, along with the
+/// posts nobody else has saved
+pub async fn delete_account(
+    state: Data<AppState>,
+    req: HttpRequest,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> Result<HttpResponse, EchoError> {
+    let claims = req_user.ok_or(EchoError::Unauthorized)?.into_inner();
+
+    let mut tx = state.db_pool.begin().await?;
+
+    // Refresh tokens, passkeys & provider links go with the user
+    let saved_posts = sqlx::query_scalar::<_, Vec<i64>>("DELETE FROM users WHERE id = $1 RETURNING saved_posts")
+        .bind(claims.id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(EchoError::Unauthorized)?;
+    let purged = sqlx::query(
+        "DELETE FROM posts
+        WHERE post_id = ANY($1)
+            AND NOT EXISTS (SELECT 1 FROM users WHERE posts.post_id = ANY(users.saved_posts))",
+    )
+    .bind(&saved_posts)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    // The token stays denied until it would've expired anyway
+    sqlx::query(
+        "INSERT INTO revoked_tokens (jti, expires_at) 
+        VALUES ($1, to_timestamp($2)) 
+        ON CONFLICT DO NOTHING",
+    )
+    .bind(claims.jti)
+    .bind(claims.exp)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
 
-            Ok(HttpResponse::Ok().insert_header(("HX-Location", "https://echo.antoniohickey.com/")).body(token_str))
+    log::info!("deleted account: user_id={} purged_posts={}", claims.id, purged);
+
+    let mut response = HttpResponse::NoContent();
+    if req.cookie(SESSION_COOKIE).is_some() {
+        let (mut session, mut csrf) = session_cookies(String::new());
+        session.make_removal();
+        csrf.make_removal();
+        response.cookie(session).cookie(csrf).insert_header(("HX-Location", "/"));
+    }
+    Ok(response.finish())
+}
+
+#[get("/.well-known/jwks.json")]
+/// Endpoint serving the public keys Echo tokens can be verified with
+pub async fn get_jwks(state: Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.jwt_keys.jwks())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign_up(username: &str, password: Option<&str>) -> Result<(String, Option<String>), Vec<&'static str>> {
+        let form = SignUpForm { username: username.to_string(), password: password.map(String::from) };
+        match SignUp::try_from(form) {
+            Ok(sign_up) => Ok((sign_up.username, sign_up.password)),
+            Err(invalid) => Err(invalid.errors.iter().map(|error| error.field).collect()),
         }
-        Err(e) => {
-            println!("{:?}", e);
-            Ok(HttpResponse::InternalServerError().body(""))
+    }
+
+    #[test]
+    fn usernames_are_3_to_32_characters() {
+        assert_eq!(validate_username("abc").ok(), Some("abc"));
+        assert_eq!(validate_username(&"a".repeat(32)).ok(), Some("a".repeat(32).as_str()));
+        assert_eq!(validate_username("  alice  ").ok(), Some("alice"));
+
+        for username in ["", "   ", "ab", " ab ", &"a".repeat(33)] {
+            assert!(validate_username(username).is_err(), "{:?}", username);
         }
+        assert_eq!(validate_username("").unwrap_err().message, "is required");
     }
-}
 
+    #[test]
+    fn usernames_are_ascii_letters_digits_and_separators() {
+        for username in ["Alice", "alice_b", "a-l.i_c3", "420", "ALICE"] {
+            assert_eq!(validate_username(username).ok(), Some(username));
+        }
+
+        // Non ascii letters count as one character, but aren't allowed
+        for username in ["josé", "ålice", "алиса", "alice😀", "ａｌｉｃｅ", "al ice", "alice!", "_alice", ".alice", "-alice"] {
+            assert!(validate_username(username).is_err(), "{:?}", username);
+        }
+        assert!(validate_username(&"é".repeat(32)).unwrap_err().message.starts_with("must be letters"));
+        assert!(validate_username(&"é".repeat(33)).unwrap_err().message.starts_with("must be 3 to 32"));
+    }
+
+    #[test]
+    fn reserved_usernames_are_rejected_in_any_case() {
+        for username in ["admin", "Admin", "ROOT", "eChO", " support "] {
+            assert_eq!(validate_username(username).unwrap_err().message, "is reserved", "{:?}", username);
+        }
+        assert_eq!(validate_username("admin2").ok(), Some("admin2"));
+    }
+
+    #[test]
+    fn sign_ups_without_a_password_are_anonymous() {
+        assert_eq!(sign_up("alice", None), Ok((String::from("alice"), None)));
+        assert_eq!(sign_up("alice", Some("")), Ok((String::from("alice"), None)));
+    }
+
+    #[test]
+    fn passwords_are_8_to_128_characters() {
+        for password in ["a".repeat(8), "a".repeat(128), "é".repeat(8), "🔑".repeat(128)] {
+            assert_eq!(sign_up("alice", Some(&password)), Ok((String::from("alice"), Some(password.clone()))));
+        }
+        for password in ["a".repeat(7), "a".repeat(129), "é".repeat(7)] {
+            assert_eq!(sign_up("alice", Some(&password)), Err(vec!["password"]), "{:?}", password);
+        }
+    }
+
+    #[test]
+    fn sign_ups_report_every_invalid_field() {
+        assert_eq!(sign_up("", Some("short")), Err(vec!["username", "password"]));
+        assert_eq!(sign_up("admin", Some("a".repeat(129).as_str())), Err(vec!["username", "password"]));
+    }
+}
@@ -1,16 +1,98 @@
+use crate::{keys::JwtKeys, oidc::OidcProvider, rate_limit::{ApiLimits, AuthLimits}, webauthn::RelyingParty};
+use chrono::Utc;
 use sqlx::PgPool;
-use std::sync::Mutex;
+use std::{sync::atomic::AtomicU32, time::Duration};
 use uuid::Uuid;
 use serde::{Serialize, Deserialize};
+use utoipa::ToSchema;
 
 #[derive(Debug)]
 pub struct AppState {
-    pub active_cnx: Mutex<u32>,
+    /// Requests being handled right now
+    pub in_flight_requests: AtomicU32,
     pub db_pool: PgPool,
+    /// Largest request body accepted, in bytes
     pub max_payload_size: usize,
+    /// Whether accounts can sign up without a password, getting a hash key instead
+    pub anonymous_sign_up: bool,
+    /// Where the website is served from, without the trailing `/`
+    pub site_url: String,
+    pub jwt_keys: JwtKeys,
+    /// Provider to sign in with, `None` when OpenID Connect sign in is off
+    pub oidc: Option<OidcProvider>,
+    /// Who passkeys are registered with
+    pub relying_party: RelyingParty,
+    /// Limits on guessing at sign ins
+    pub auth_limits: AuthLimits,
+    /// Request quotas for the api scopes
+    pub api_limits: ApiLimits,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State for handlers under test, its database pool never connects unless queried
+    pub fn for_tests(config: &crate::config::Config) -> Self {
+        AppState {
+            in_flight_requests: AtomicU32::new(0),
+            db_pool: PgPool::connect_lazy(config.db_url()).unwrap(),
+            max_payload_size: config.server.max_payload_size,
+            anonymous_sign_up: config.anonymous_sign_up,
+            site_url: config.site_url.clone(),
+            jwt_keys: JwtKeys::from_config(&config.jwt).unwrap(),
+            oidc: None,
+            relying_party: RelyingParty::new(&config.webauthn),
+            auth_limits: AuthLimits::new(&config.rate_limits, config.server.trust_forwarded_for),
+            api_limits: ApiLimits::new(&config.rate_limits, config.server.trust_forwarded_for),
+        }
+    }
+}
+
+/// What a user is allowed to do
+#[derive(Serialize, Deserialize, sqlx::Type, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    /// Can use the `/admin` routes
+    Admin,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TokenClaims {
     pub id: Uuid,
+    /// Tokens from before roles are plain users
+    #[serde(default)]
+    pub role: Role,
+    /// Expires at, unix seconds
+    pub exp: i64,
+    /// Issued at, unix seconds
+    pub iat: i64,
+    /// Token id, for revoking the token on logout
+    pub jti: Uuid,
+}
+impl TokenClaims {
+    /// Claims for a new token for the user that's valid for `ttl`
+    pub fn new(id: Uuid, role: Role, ttl: Duration) -> Self {
+        let iat = Utc::now().timestamp();
+        TokenClaims { id, role, exp: iat + ttl.as_secs() as i64, iat, jti: Uuid::new_v4() }
+    }
+}
+
+/// A problem with one field of a submitted payload
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+impl FieldError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        FieldError { field, message: message.into() }
+    }
+}
+
+/// Every problem found with a submitted payload
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
 }
@@ -0,0 +1,149 @@
+//! Html fragments returned to htmx.
+//!
+//! Templates ending in `.html` are escaped by askama, so untrusted post
+//! fields are safe in text & quoted attributes. Urls going into an `href`
+//! must go through `Post::link` which only lets http(s) urls through.
+use crate::{routes::{posts::Post, user::Account}, structs::FieldError};
+use askama::Template;
+
+
+/// Cards for a list of posts (the feed & saved posts)
+#[derive(Template)]
+#[template(path = "post_cards.html")]
+pub struct PostCards<'a> {
+    pub posts: &'a [Post],
+}
+
+/// Replaces a post's save button once it's saved
+#[derive(Template)]
+#[template(path = "saved_button.html")]
+pub struct SavedButton;
+
+/// A failed request, shown in place of what was requested
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct Error<'a> {
+    pub message: &'a str,
+}
+
+/// Field level problems with a submitted form
+#[derive(Template)]
+#[template(path = "field_errors.html")]
+pub struct FieldErrors<'a> {
+    pub errors: &'a [FieldError],
+}
+
+/// Secret url of a user's saved posts feed
+#[derive(Template)]
+#[template(path = "feed_token.html")]
+pub struct FeedToken<'a> {
+    pub feed_url: &'a str,
+}
+
+/// Where the feed url was, once it's revoked
+#[derive(Template)]
+#[template(path = "feed_token_revoked.html")]
+pub struct FeedTokenRevoked;
+
+/// Confirms a password account was created
+#[derive(Template)]
+#[template(path = "signed_up.html")]
+pub struct SignedUp<'a> {
+    pub username: &'a str,
+}
+
+/// Link to sign in with the OpenID Connect provider
+#[derive(Template)]
+#[template(path = "oidc_button.html")]
+pub struct OidcButton;
+
+/// Hash key handed out after signing up
+#[derive(Template)]
+#[template(path = "sign_up_key.html")]
+pub struct SignUpKey<'a> {
+    pub hash: &'a str,
+}
+
+/// The signed in user's account settings
+#[derive(Template)]
+#[template(path = "account.html")]
+pub struct AccountSettings<'a> {
+    pub account: &'a Account,
+}
+
+/// Hash key handed out after regenerating it
+#[derive(Template)]
+#[template(path = "new_key.html")]
+pub struct NewKey<'a> {
+    pub key: &'a str,
+}
+
+/// Whether the username typed into the sign up page is free
+#[derive(Template)]
+#[template(path = "username_available.html")]
+pub struct UsernameAvailable<'a> {
+    pub available: bool,
+    pub message: Option<&'a str>,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(title: &str, author: &str, url: &str) -> Post {
+        Post {
+            id: "8863".to_string(),
+            title: title.to_string(),
+            author: author.to_string(),
+            url: url.to_string(),
+            timestamp: "1175714200".to_string(),
+        }
+    }
+
+    fn render(post: Post) -> String {
+        PostCards { posts: &[post] }.render().unwrap()
+    }
+
+    #[test]
+    fn escapes_script_in_title() {
+        let html = render(post("<script>alert(1)</script>", "pg", "https://example.com"));
+
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+    }
+
+    #[test]
+    fn title_cannot_break_out_of_attributes() {
+        let html = render(post("x' onmouseover='alert(1)", "\" onfocus=\"alert(1)", "https://example.com"));
+
+        assert!(!html.contains("onmouseover='alert(1)"));
+        assert!(!html.contains("onfocus=\"alert(1)"));
+    }
+
+    #[test]
+    fn rejects_javascript_urls() {
+        for url in ["javascript:alert(1)", "JaVaScRiPt:alert(1)", " javascript:alert(1)", "data:text/html,<script>alert(1)</script>"] {
+            let html = render(post("title", "pg", url));
+
+            assert!(html.contains("<a href='https://news.ycombinator.com/item?id=8863'>"), "{}", url);
+        }
+    }
+
+    #[test]
+    fn keeps_http_urls() {
+        let html = render(post("title", "pg", "https://example.com/a?b=1&c=2"));
+
+        assert!(html.contains("<a href='https://example.com/a?b=1&amp;c=2'>"));
+    }
+
+    #[test]
+    fn revoking_the_feed_url_swaps_out_its_fragment() {
+        let feed_token = FeedToken { feed_url: "https://example.com/posts/saved/abc/feed.atom" }.render().unwrap();
+        let revoked = FeedTokenRevoked.render().unwrap();
+
+        // htmx swaps the revoked fragment in for the feed url's, by its id
+        assert!(feed_token.starts_with("<div id='feed-token'") && revoked.starts_with("<div id='feed-token'"));
+        assert!(!revoked.contains("feed.atom"));
+    }
+}
@@ -0,0 +1,326 @@
+use crate::config::WebauthnConfig;
+use ring::signature::{self, UnparsedPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+
+// Just enough WebAuthn (https://www.w3.org/TR/webauthn-2/) for passkeys
+// without attestation. Browsers hand us the credential's public key as
+// SPKI (`getPublicKey()`) & the raw authenticator data, so there's no
+// CBOR to pick apart.
+
+/// COSE algorithms we accept credentials for
+pub const ES256: i32 = -7;
+pub const EDDSA: i32 = -8;
+pub const RS256: i32 = -257;
+pub const ALGORITHMS: [i32; 3] = [ES256, EDDSA, RS256];
+
+/// How long the user has to finish a passkey ceremony
+pub const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// Us, as far as authenticators are concerned
+#[derive(Debug)]
+pub struct RelyingParty {
+    /// Domain credentials are scoped to
+    pub id: String,
+    /// Origin the website is served from
+    pub origin: String,
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+/// The parts of the authenticator data we use
+#[derive(Debug)]
+pub struct AuthenticatorData<'a> {
+    pub sign_count: u32,
+    /// Only there when registering
+    pub credential_id: Option<&'a [u8]>,
+}
+
+impl RelyingParty {
+    pub fn new(config: &WebauthnConfig) -> Self {
+        RelyingParty { id: config.rp_id.clone(), origin: config.origin.clone(), name: String::from("Echo") }
+    }
+
+    /// The challenge in the client data, once it's checked to be
+    /// for this `ceremony` (`webauthn.create` or `webauthn.get`) on our origin
+    pub fn verify_client_data(&self, client_data_json: &[u8], ceremony: &str) -> Result<String, &'static str> {
+        let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(|_| "malformed client data")?;
+        if client_data.ceremony != ceremony {
+            return Err("client data is for another ceremony");
+        }
+        if client_data.origin != self.origin {
+            return Err("client data is from another origin");
+        }
+
+        Ok(client_data.challenge)
+    }
+
+    /// Parses the authenticator data, checking it's scoped to
+    /// our RP ID & the user was there for it
+    pub fn verify_authenticator_data<'a>(&self, auth_data: &'a [u8]) -> Result<AuthenticatorData<'a>, &'static str> {
+        if auth_data.len() < 37 {
+            return Err("authenticator data is too short");
+        }
+        if auth_data[..32] != Sha256::digest(self.id.as_bytes())[..] {
+            return Err("authenticator data is for another relying party");
+        }
+        let flags = auth_data[32];
+        if flags & FLAG_USER_PRESENT == 0 {
+            return Err("user wasn't present");
+        }
+        let sign_count = u32::from_be_bytes([auth_data[33], auth_data[34], auth_data[35], auth_data[36]]);
+
+        // Attested credential data is the 16 byte AAGUID, then the id's length & the id
+        let credential_id = match flags & FLAG_ATTESTED_CREDENTIAL {
+            0 => None,
+            _ => {
+                let attested = auth_data.get(37 + 16..).ok_or("attested credential data is too short")?;
+                let (len, rest) = match attested {
+                    [high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+                    _ => return Err("attested credential data is too short"),
+                };
+                Some(rest.get(..len).ok_or("attested credential data is too short")?)
+            }
+        };
+
+        Ok(AuthenticatorData { sign_count, credential_id })
+    }
+}
+
+/// Whether a credential's SPKI public key is usable for the COSE algorithm
+pub fn is_usable_key(algorithm: i32, public_key_spki: &[u8]) -> bool {
+    ALGORITHMS.contains(&algorithm) && spki_public_key(public_key_spki).is_some()
+}
+
+/// Checks an assertion's signature, which covers the authenticator
+/// data followed by the hash of the client data
+pub fn verify_signature(
+    algorithm: i32,
+    public_key_spki: &[u8],
+    auth_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> bool {
+    let public_key = match spki_public_key(public_key_spki) {
+        Some(public_key) => public_key,
+        None => return false,
+    };
+    let verification: &dyn signature::VerificationAlgorithm = match algorithm {
+        ES256 => &signature::ECDSA_P256_SHA256_ASN1,
+        EDDSA => &signature::ED25519,
+        RS256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+        _ => return false,
+    };
+
+    let mut signed = auth_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    UnparsedPublicKey::new(verification, public_key).verify(&signed, signature).is_ok()
+}
+
+/// Whether an assertion's sign count is usable after the `stored` one, counting
+/// authenticators have to count up, or the credential may've been cloned
+pub fn is_sign_count_valid(stored: i64, sign_count: u32) -> bool {
+    let sign_count = i64::from(sign_count);
+    (sign_count == 0 && stored == 0) || sign_count > stored
+}
+
+/// The key out of an SPKI's `subjectPublicKey` bit string, which is what
+/// ring takes for each of our algorithms (EC point, Ed25519 key, PKCS#1 RSA key)
+fn spki_public_key(der: &[u8]) -> Option<&[u8]> {
+    let (spki, _) = der_element(der, 0x30)?;
+    let (_algorithm, rest) = der_element(spki, 0x30)?;
+    let (bits, _) = der_element(rest, 0x03)?;
+    match bits.split_first()? {
+        (0, public_key) => Some(public_key),
+        _ => None,
+    }
+}
+
+/// Splits the DER element with `tag` off the front of `der`, into its contents & the rest
+fn der_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&found_tag, rest) = der.split_first()?;
+    if found_tag != tag {
+        return None;
+    }
+    let (&len_byte, rest) = rest.split_first()?;
+    let (len, rest) = match len_byte & 0x80 {
+        0 => (len_byte as usize, rest),
+        _ => {
+            let len_len = (len_byte & 0x7f) as usize;
+            if len_len == 0 || len_len > 4 || rest.len() < len_len {
+                return None;
+            }
+            let len = rest[..len_len].iter().fold(0usize, |len, &byte| (len << 8) | byte as usize);
+            (len, &rest[len_len..])
+        }
+    };
+
+    (rest.len() >= len).then(|| (&rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    // An assertion recorded from a software authenticator for `echo.antoniohickey.com`,
+    // with a sign count of 7, & signed once with each key
+    const CHALLENGE: &str = "cmVjb3JkZWQtZml4dHVyZS1jaGFsbGVuZ2UtMDAwMQ";
+    const CLIENT_DATA: &str = "eyJ0eXBlIjoid2ViYXV0aG4uZ2V0IiwiY2hhbGxlbmdlIjoiY21WamIzSmtaV1F0Wm1sNGRIVnlaUzFqYUdGc2JHVnVaMlV0TURBd01RIiwib3JpZ2luIjoiaHR0cHM6Ly9lY2hvLmFudG9uaW9oaWNrZXkuY29tIiwiY3Jvc3NPcmlnaW4iOmZhbHNlfQ";
+    const AUTH_DATA: &str = "BYVMD8ubGM46iqEYxFY5I1Vaiq1LpFZMuCR99oXZRHwFAAAABw";
+    const ES256_KEY: &str = "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAELrESg2pnURzvQttP5kLh5iDhdkL5eBICYriucVi-PXcMqL_595D_YqK_cASpu7Jyryryn9r4NfmAZzOgFcca9g";
+    const ES256_SIGNATURE: &str = "MEQCIBM_SJA8BX2zmzkVwyLn6WKz32rqNpd6o-Cdo_L8cEbuAiAkNQts7RV8UaHpSTeQOzriPghEf0Qvbx9TSXBFZQAqDQ";
+    const EDDSA_KEY: &str = "MCowBQYDK2VwAyEAldTb_fNkCwa1Qb7YYuhvjq1L9GRSvTqSCrRfSTZ3EUk";
+    const EDDSA_SIGNATURE: &str = "8EI4CcNv4uF8BqKmX97UN3hC7Iaes8qJ4yWzIpyK-s2iSlBJvhSnevisPjiWfWoBAxB5YixcWSCQYxBTdRapBg";
+    // Registration authenticator data, for credential id 0x01..=0x10
+    const REGISTRATION_AUTH_DATA: &str = "BYVMD8ubGM46iqEYxFY5I1Vaiq1LpFZMuCR99oXZRHxFAAAAAAAAAAAAAAAAAAAAAAAAAAAAEAECAwQFBgcICQoLDA0ODxCg";
+
+    fn bytes(val: &str) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(val).unwrap()
+    }
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty {
+            id: String::from("echo.antoniohickey.com"),
+            origin: String::from("https://echo.antoniohickey.com"),
+            name: String::from("Echo"),
+        }
+    }
+
+    fn verify(algorithm: i32, key: &str, auth_data: &[u8], client_data: &[u8], signature: &[u8]) -> bool {
+        verify_signature(algorithm, &bytes(key), auth_data, client_data, signature)
+    }
+
+    #[test]
+    fn accepts_recorded_assertion() {
+        let rp = relying_party();
+        let (client_data, auth_data) = (bytes(CLIENT_DATA), bytes(AUTH_DATA));
+
+        assert_eq!(rp.verify_client_data(&client_data, "webauthn.get").unwrap(), CHALLENGE);
+        let parsed = rp.verify_authenticator_data(&auth_data).unwrap();
+        assert_eq!(parsed.sign_count, 7);
+        assert_eq!(parsed.credential_id, None);
+        assert!(verify(ES256, ES256_KEY, &auth_data, &client_data, &bytes(ES256_SIGNATURE)));
+        assert!(verify(EDDSA, EDDSA_KEY, &auth_data, &client_data, &bytes(EDDSA_SIGNATURE)));
+    }
+
+    #[test]
+    fn parses_registration_credential_id() {
+        let auth_data = bytes(REGISTRATION_AUTH_DATA);
+        let parsed = relying_party().verify_authenticator_data(&auth_data).unwrap();
+
+        assert_eq!(parsed.credential_id, Some(&(1..=16).collect::<Vec<u8>>()[..]));
+        assert!(relying_party().verify_authenticator_data(&auth_data[..auth_data.len() - 10]).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_ceremony() {
+        assert!(relying_party().verify_client_data(&bytes(CLIENT_DATA), "webauthn.create").is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_origin() {
+        let rp = RelyingParty { origin: String::from("https://evil.example"), ..relying_party() };
+        assert!(rp.verify_client_data(&bytes(CLIENT_DATA), "webauthn.get").is_err());
+
+        let client_data = String::from_utf8(bytes(CLIENT_DATA)).unwrap().replace("https://echo.", "https://evil.");
+        assert!(relying_party().verify_client_data(client_data.as_bytes(), "webauthn.get").is_err());
+        assert!(relying_party().verify_client_data(b"not json", "webauthn.get").is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_challenge() {
+        let client_data = String::from_utf8(bytes(CLIENT_DATA)).unwrap().replace(CHALLENGE, "c29tZS1vdGhlci1jaGFsbGVuZ2U");
+        let challenge = relying_party().verify_client_data(client_data.as_bytes(), "webauthn.get").unwrap();
+
+        // The challenge is looked up by the route, & the signature covers it
+        assert_ne!(challenge, CHALLENGE);
+        assert!(!verify(ES256, ES256_KEY, &bytes(AUTH_DATA), client_data.as_bytes(), &bytes(ES256_SIGNATURE)));
+    }
+
+    #[test]
+    fn rejects_wrong_rp_id() {
+        let rp = RelyingParty { id: String::from("evil.example"), ..relying_party() };
+        assert!(rp.verify_authenticator_data(&bytes(AUTH_DATA)).is_err());
+    }
+
+    #[test]
+    fn rejects_user_not_present() {
+        let mut auth_data = bytes(AUTH_DATA);
+        auth_data[32] &= !FLAG_USER_PRESENT;
+
+        assert!(relying_party().verify_authenticator_data(&auth_data).is_err());
+        assert!(!verify(ES256, ES256_KEY, &auth_data, &bytes(CLIENT_DATA), &bytes(ES256_SIGNATURE)));
+    }
+
+    #[test]
+    fn rejects_short_authenticator_data() {
+        assert!(relying_party().verify_authenticator_data(&bytes(AUTH_DATA)[..36]).is_err());
+    }
+
+    #[test]
+    fn sign_count_has_to_increase() {
+        assert!(is_sign_count_valid(6, 7));
+        assert!(!is_sign_count_valid(7, 7));
+        assert!(!is_sign_count_valid(8, 7));
+        // Authenticators that don't count always send 0
+        assert!(is_sign_count_valid(0, 0));
+        assert!(!is_sign_count_valid(7, 0));
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let (auth_data, client_data) = (bytes(AUTH_DATA), bytes(CLIENT_DATA));
+        let mut signature = bytes(ES256_SIGNATURE);
+        let last = signature.len() - 1;
+        signature[last] ^= 1;
+
+        assert!(!verify(ES256, ES256_KEY, &auth_data, &client_data, &signature));
+        // Right signature, wrong key or algorithm
+        assert!(!verify(ES256, ES256_KEY, &auth_data, &client_data, &bytes(EDDSA_SIGNATURE)));
+        assert!(!verify(EDDSA, ES256_KEY, &auth_data, &client_data, &bytes(ES256_SIGNATURE)));
+        assert!(!verify(-36, ES256_KEY, &auth_data, &client_data, &bytes(ES256_SIGNATURE)));
+        // Signed count changed after the fact
+        let mut recounted = auth_data.clone();
+        recounted[36] = 8;
+        assert!(!verify(ES256, ES256_KEY, &recounted, &client_data, &bytes(ES256_SIGNATURE)));
+    }
+
+    #[test]
+    fn rejects_malformed_der() {
+        let key = bytes(ES256_KEY);
+        assert!(is_usable_key(ES256, &key));
+        assert!(!is_usable_key(-36, &key));
+
+        let mut wrong_tag = key.clone();
+        wrong_tag[0] = 0x31;
+        let mut long_len = key.clone();
+        long_len[1] = 0x85;
+        let mut padded_bits = key.clone();
+        padded_bits[25] = 1;
+        for malformed in [&key[..key.len() - 1], &key[..2], &[][..], &wrong_tag, &long_len, &padded_bits] {
+            assert!(!is_usable_key(ES256, malformed));
+            assert!(!verify_signature(ES256, malformed, &bytes(AUTH_DATA), &bytes(CLIENT_DATA), &bytes(ES256_SIGNATURE)));
+        }
+    }
+
+    #[test]
+    fn parses_long_form_der_lengths() {
+        let mut der = vec![0x30, 0x81, 0x80];
+        der.extend([0u8; 0x80]);
+        assert_eq!(der_element(&der, 0x30).map(|(contents, rest)| (contents.len(), rest.len())), Some((0x80, 0)));
+        assert_eq!(der_element(&[0x30, 0x80], 0x30), None);
+        assert_eq!(der_element(&[0x30, 0x82, 0x01], 0x30), None);
+    }
+}